@@ -3,28 +3,52 @@
 // the decoder is safe and avoids re-validating every string record twice.
 use ::maxminddb as maxminddb_crate;
 use arc_swap::{ArcSwapOption, Guard};
-use ipnetwork::IpNetwork;
+use flate2::read::MultiGzDecoder;
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 use magnus::{
-    error::Error, prelude::*, scan_args::get_kwargs, scan_args::scan_args, value::Lazy,
-    ExceptionClass, IntoValue, RArray, RClass, RHash, RModule, RString, Symbol, Value,
+    error::Error,
+    gc::Marker,
+    prelude::*,
+    scan_args::get_kwargs,
+    scan_args::scan_args,
+    typed_data::Obj,
+    value::{BoxValue, Lazy, Opaque},
+    DataTypeFunctions, ExceptionClass, IntoValue, KwArgs, RArray, RClass, RHash, RModule, RString,
+    Symbol, Value,
+};
+use maxminddb_crate::{
+    LookupResult, MaxMindDbError, PathElement, Reader as MaxMindReader, Within, WithinOptions,
 };
-use maxminddb_crate::{MaxMindDbError, Reader as MaxMindReader, Within};
 use memmap2::Mmap;
-use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::de::{
+    self, Deserialize, DeserializeOwned, DeserializeSeed, Deserializer, IgnoredAny, MapAccess,
+    SeqAccess, Visitor,
+};
 use std::{
     borrow::Cow,
-    collections::BTreeMap,
+    cell::{Cell, OnceCell},
+    collections::{BTreeMap, HashMap, VecDeque},
+    ffi::{c_void, OsStr},
     fmt,
     fs::File,
-    io::Read as IoRead,
-    net::IpAddr,
+    io::{self, BufWriter, Read as IoRead, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    panic::{self, AssertUnwindSafe},
     path::Path,
+    ptr::{self, NonNull},
     str::FromStr,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, Weak,
     },
+    time::{Duration, Instant, SystemTime},
 };
+use tar::Archive as TarArchive;
+
+mod overlay;
+mod writer;
+
+use writer::{Writer, WriterValue};
 
 // Error constants
 const ERR_CLOSED_DB: &str = "Attempt to read from a closed MaxMind DB.";
@@ -226,30 +250,279 @@ impl<'de> Deserialize<'de> for RubyDecodedValue {
         D: Deserializer<'de>,
     {
         let ruby = magnus::Ruby::get().expect("Ruby VM should be available in deserializer");
-        RubyValueSeed { ruby: &ruby }.deserialize(deserializer)
+        let options = DECODE_OPTIONS.with(Cell::get);
+        // SAFETY: `with_decode_options` only publishes a pointer for as long as the
+        // options it borrows are alive, and restores the previous value on exit.
+        let options = options.map_or(&DEFAULT_DECODE_OPTIONS, |ptr| unsafe { ptr.as_ref() });
+        RubyValueSeed {
+            ruby: &ruby,
+            options,
+            context: options.root,
+        }
+        .deserialize(deserializer)
+    }
+}
+
+/// Options controlling how records are converted into Ruby objects
+#[derive(Clone, Default)]
+struct DecodeOptions {
+    /// Top-level keys to keep. All other keys are skipped without being decoded.
+    fields: Option<Arc<[String]>>,
+    /// Languages to keep in `names` maps, in order of preference
+    locales: Option<Arc<[String]>>,
+    /// Replace each `names` map with the name in the most preferred available locale
+    collapse_names: bool,
+    /// Whether map keys become Strings or Symbols
+    keys: KeyStyle,
+    /// Deep-freeze decoded Hashes, Arrays, and Strings
+    freeze: bool,
+    /// Where the decoded value sits within the record
+    root: ValueContext,
+}
+
+static DEFAULT_DECODE_OPTIONS: DecodeOptions = DecodeOptions {
+    fields: None,
+    locales: None,
+    collapse_names: false,
+    keys: KeyStyle::String,
+    freeze: false,
+    root: ValueContext::Record,
+};
+
+impl DecodeOptions {
+    /// Options for decoding the value at the end of `path` rather than a whole record
+    fn for_path(&self, path: &[PathSegment]) -> DecodeOptions {
+        let root = match path.last() {
+            None => ValueContext::Record,
+            Some(PathSegment::Key(key)) if key == "names" => ValueContext::Names,
+            Some(_) => ValueContext::Nested,
+        };
+        DecodeOptions {
+            fields: if path.is_empty() {
+                self.fields.clone()
+            } else {
+                None
+            },
+            root,
+            ..self.clone()
+        }
+    }
+
+    /// Apply the `keys:` and `freeze:` options
+    fn set_output(
+        &mut self,
+        ruby: &magnus::Ruby,
+        keys: Option<Symbol>,
+        freeze: Option<bool>,
+    ) -> Result<(), Error> {
+        if let Some(keys) = keys {
+            self.keys = KeyStyle::from_symbol(ruby, keys)?;
+        }
+        if let Some(freeze) = freeze {
+            self.freeze = freeze;
+        }
+        Ok(())
+    }
+
+    /// Apply the `locales:` and `collapse_names:` options
+    fn set_locales(
+        &mut self,
+        ruby: &magnus::Ruby,
+        locales: Option<RArray>,
+        collapse_names: Option<bool>,
+    ) -> Result<(), Error> {
+        if let Some(locales) = locales {
+            self.locales = Some(parse_names(ruby, locales, "Locales")?);
+        }
+        if let Some(collapse_names) = collapse_names {
+            self.collapse_names = collapse_names;
+        }
+        if self.collapse_names && self.locales.is_none() {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "collapse_names requires the locales option",
+            ));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn keep_field(&self, key: &str) -> bool {
+        match &self.fields {
+            Some(fields) => fields.iter().any(|field| field == key),
+            None => true,
+        }
     }
+
+    /// Position of `locale` in the preferred locales, or None if it should be dropped
+    #[inline]
+    fn locale_rank(&self, locale: &str) -> Option<usize> {
+        match &self.locales {
+            Some(locales) => locales.iter().position(|l| l == locale),
+            None => Some(0),
+        }
+    }
+}
+
+/// The Ruby type used for map keys in decoded records
+#[derive(Clone, Copy, Default)]
+enum KeyStyle {
+    /// Frozen Strings (the default)
+    #[default]
+    String,
+    /// Symbols
+    Symbol,
+}
+
+impl KeyStyle {
+    fn from_symbol(ruby: &magnus::Ruby, symbol: Symbol) -> Result<Self, Error> {
+        match &*symbol.name()? {
+            "string" => Ok(KeyStyle::String),
+            "symbol" => Ok(KeyStyle::Symbol),
+            other => Err(Error::new(
+                ruby.exception_arg_error(),
+                format!(
+                    "Unsupported keys value: {} (expected :string or :symbol)",
+                    other
+                ),
+            )),
+        }
+    }
+}
+
+/// Where a value sits within a record, which determines the options that apply to it
+#[derive(Clone, Copy, Default)]
+enum ValueContext {
+    /// The record itself, where field projection applies
+    #[default]
+    Record,
+    /// Any value nested inside the record
+    Nested,
+    /// The value of a `names` key, where locale filtering applies
+    Names,
 }
 
-struct RubyValueSeed<'ruby> {
-    ruby: &'ruby magnus::Ruby,
+thread_local! {
+    /// Decode options for the `RubyDecodedValue` currently being deserialized.
+    ///
+    /// The maxminddb crate decodes through `Deserialize` rather than a seed, so the
+    /// options are handed to `RubyDecodedValue::deserialize` through this slot.
+    static DECODE_OPTIONS: Cell<Option<NonNull<DecodeOptions>>> = const { Cell::new(None) };
 }
 
-impl<'ruby, 'de> DeserializeSeed<'de> for RubyValueSeed<'ruby> {
+/// Run `func` with `options` applied to every `RubyDecodedValue` it decodes
+#[inline]
+fn with_decode_options<R>(options: &DecodeOptions, func: impl FnOnce() -> R) -> R {
+    struct Restore(Option<NonNull<DecodeOptions>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            DECODE_OPTIONS.with(|cell| cell.set(self.0));
+        }
+    }
+
+    let _restore = Restore(DECODE_OPTIONS.with(|cell| cell.replace(Some(NonNull::from(options)))));
+    func()
+}
+
+struct RubyValueSeed<'a> {
+    ruby: &'a magnus::Ruby,
+    options: &'a DecodeOptions,
+    context: ValueContext,
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for RubyValueSeed<'a> {
     type Value = RubyDecodedValue;
 
     fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_any(RubyValueVisitor { ruby: self.ruby })
+        deserializer.deserialize_any(RubyValueVisitor {
+            ruby: self.ruby,
+            options: self.options,
+            context: self.context,
+        })
     }
 }
 
-struct RubyValueVisitor<'ruby> {
-    ruby: &'ruby magnus::Ruby,
+struct RubyValueVisitor<'a> {
+    ruby: &'a magnus::Ruby,
+    options: &'a DecodeOptions,
+    context: ValueContext,
+}
+
+impl<'a> RubyValueVisitor<'a> {
+    /// Seed for a value nested inside the one being visited
+    #[inline]
+    fn child(&self, context: ValueContext) -> RubyValueSeed<'a> {
+        RubyValueSeed {
+            ruby: self.ruby,
+            options: self.options,
+            context,
+        }
+    }
+
+    /// Convert a map key into a frozen String or a Symbol, depending on the `keys:` option
+    #[inline]
+    fn map_key(&self, key: &str) -> Value {
+        match self.options.keys {
+            KeyStyle::String => interned_key(self.ruby, key)
+                .unwrap_or_else(|| self.ruby.str_new(key).into_value_with(self.ruby)),
+            KeyStyle::Symbol => self.ruby.to_symbol(key).into_value_with(self.ruby),
+        }
+    }
+
+    /// Wrap a decoded String, Array, or Hash, freezing it if the `freeze:` option is set
+    #[inline]
+    fn finish(&self, value: Value) -> RubyDecodedValue {
+        if self.options.freeze {
+            value.freeze();
+        }
+        RubyDecodedValue::new(value)
+    }
+
+    /// Decode a `names` map, keeping only the configured locales.
+    ///
+    /// With `collapse_names`, returns the name in the most preferred locale present in
+    /// the map, or nil if none are.
+    fn visit_names<'de, A>(self, mut map: A) -> Result<RubyDecodedValue, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        if self.options.collapse_names {
+            let mut best: Option<(usize, RubyDecodedValue)> = None;
+            while let Some(key) = map.next_key::<Cow<'de, str>>()? {
+                match self.options.locale_rank(key.as_ref()) {
+                    Some(rank) if best.as_ref().map_or(true, |(best, _)| rank < *best) => {
+                        best = Some((rank, map.next_value_seed(self.child(ValueContext::Nested))?));
+                    }
+                    _ => {
+                        map.next_value::<IgnoredAny>()?;
+                    }
+                }
+            }
+            return Ok(best.map_or_else(
+                || RubyDecodedValue::new(self.ruby.qnil().as_value()),
+                |(_, value)| value,
+            ));
+        }
+
+        let hash = self.ruby.hash_new();
+        while let Some(key) = map.next_key::<Cow<'de, str>>()? {
+            if self.options.locale_rank(key.as_ref()).is_none() {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            }
+            let value = map.next_value_seed(self.child(ValueContext::Nested))?;
+            hash.aset(self.map_key(key.as_ref()), value.into_value())
+                .map_err(|e| de::Error::custom(e.to_string()))?;
+        }
+        Ok(self.finish(hash.into_value_with(self.ruby)))
+    }
 }
 
-impl<'de, 'ruby> Visitor<'de> for RubyValueVisitor<'ruby> {
+impl<'de, 'a> Visitor<'de> for RubyValueVisitor<'a> {
     type Value = RubyDecodedValue;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
@@ -333,7 +606,7 @@ impl<'de, 'ruby> Visitor<'de> for RubyValueVisitor<'ruby> {
     {
         let val = interned_key(self.ruby, value)
             .unwrap_or_else(|| self.ruby.str_new(value).into_value_with(self.ruby));
-        Ok(RubyDecodedValue::new(val))
+        Ok(self.finish(val))
     }
 
     fn visit_string<E>(self, value: String) -> Result<Self::Value, E>
@@ -342,25 +615,21 @@ impl<'de, 'ruby> Visitor<'de> for RubyValueVisitor<'ruby> {
     {
         let val = interned_key(self.ruby, &value)
             .unwrap_or_else(|| self.ruby.str_new(&value).into_value_with(self.ruby));
-        Ok(RubyDecodedValue::new(val))
+        Ok(self.finish(val))
     }
 
     fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        Ok(RubyDecodedValue::new(
-            self.ruby.str_from_slice(value).into_value_with(self.ruby),
-        ))
+        Ok(self.finish(self.ruby.str_from_slice(value).into_value_with(self.ruby)))
     }
 
     fn visit_byte_buf<E>(self, value: Vec<u8>) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        Ok(RubyDecodedValue::new(
-            self.ruby.str_from_slice(&value).into_value_with(self.ruby),
-        ))
+        Ok(self.finish(self.ruby.str_from_slice(&value).into_value_with(self.ruby)))
     }
 
     fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
@@ -371,47 +640,131 @@ impl<'de, 'ruby> Visitor<'de> for RubyValueVisitor<'ruby> {
             Some(cap) => self.ruby.ary_new_capa(cap),
             None => self.ruby.ary_new(),
         };
-        while let Some(elem) = seq.next_element_seed(RubyValueSeed { ruby: self.ruby })? {
+        while let Some(elem) = seq.next_element_seed(self.child(ValueContext::Nested))? {
             arr.push(elem.into_value())
                 .map_err(|e| de::Error::custom(e.to_string()))?;
         }
-        Ok(RubyDecodedValue::new(arr.into_value_with(self.ruby)))
+        Ok(self.finish(arr.into_value_with(self.ruby)))
     }
 
     fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
     where
         A: MapAccess<'de>,
     {
+        let project = match self.context {
+            ValueContext::Record => self.options.fields.is_some(),
+            ValueContext::Names if self.options.locales.is_some() => {
+                return self.visit_names(map);
+            }
+            _ => false,
+        };
         let hash = match map.size_hint() {
-            Some(cap) => self.ruby.hash_new_capa(cap),
-            None => self.ruby.hash_new(),
+            Some(cap) if !project => self.ruby.hash_new_capa(cap),
+            _ => self.ruby.hash_new(),
         };
         while let Some(key) = map.next_key::<Cow<'de, str>>()? {
-            let value = map.next_value_seed(RubyValueSeed { ruby: self.ruby })?;
-            let key_val = interned_key(self.ruby, key.as_ref())
-                .unwrap_or_else(|| self.ruby.str_new(key.as_ref()).into_value_with(self.ruby));
-            hash.aset(key_val, value.into_value())
+            if project && !self.options.keep_field(key.as_ref()) {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            }
+            let context = if key.as_ref() == "names" {
+                ValueContext::Names
+            } else {
+                ValueContext::Nested
+            };
+            let value = map.next_value_seed(self.child(context))?;
+            hash.aset(self.map_key(key.as_ref()), value.into_value())
                 .map_err(|e| de::Error::custom(e.to_string()))?;
         }
-        Ok(RubyDecodedValue::new(hash.into_value_with(self.ruby)))
+        Ok(self.finish(hash.into_value_with(self.ruby)))
     }
 }
 
 /// Enum to handle different reader source types
 enum ReaderSource {
     Mmap(MaxMindReader<Mmap>),
-    Memory(MaxMindReader<Vec<u8>>),
+    Memory(MaxMindReader<MemorySource>),
+}
+
+/// Buffer backing an in-memory database
+enum MemorySource {
+    Owned(Vec<u8>),
+    Ruby(RubyBytes),
+}
+
+impl AsRef<[u8]> for MemorySource {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            MemorySource::Owned(bytes) => bytes,
+            MemorySource::Ruby(bytes) => bytes.as_ref(),
+        }
+    }
+}
+
+/// The contents of a frozen Ruby String, borrowed without copying
+struct RubyBytes {
+    /// Registered with the GC so the String is neither collected nor moved by
+    /// compaction while the database is in use
+    _string: BoxValue<RString>,
+    ptr: *const u8,
+    len: usize,
+}
+
+impl RubyBytes {
+    fn new(string: RString) -> Self {
+        // SAFETY: the String is frozen, so its buffer is never modified or reallocated
+        let bytes = unsafe { string.as_slice() };
+        RubyBytes {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+            _string: BoxValue::new(string),
+        }
+    }
+}
+
+impl AsRef<[u8]> for RubyBytes {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: see `RubyBytes::new`; the BoxValue keeps the buffer alive and in place
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
 }
 
+// SAFETY: the bytes are immutable and can be read from any thread, including
+// without the GVL. ReaderSources are only dropped by Ruby threads holding the GVL,
+// which is required to unregister the BoxValue.
+unsafe impl Send for RubyBytes {}
+unsafe impl Sync for RubyBytes {}
+
 impl ReaderSource {
     #[inline]
     fn lookup(
         &self,
         ip: IpAddr,
+        release_gvl: bool,
+        cache: Option<&CacheHandle<'_>>,
+    ) -> Result<Option<RubyDecodedValue>, maxminddb_crate::MaxMindDbError> {
+        match self {
+            ReaderSource::Mmap(reader) => {
+                decode_record(&find_record(reader, ip, release_gvl)?, cache)
+            }
+            ReaderSource::Memory(reader) => {
+                decode_record(&find_record(reader, ip, release_gvl)?, cache)
+            }
+        }
+    }
+
+    #[inline]
+    fn lookup_path(
+        &self,
+        ip: IpAddr,
+        path: &[PathElement<'_>],
+        release_gvl: bool,
     ) -> Result<Option<RubyDecodedValue>, maxminddb_crate::MaxMindDbError> {
         match self {
-            ReaderSource::Mmap(reader) => reader.lookup(ip)?.decode(),
-            ReaderSource::Memory(reader) => reader.lookup(ip)?.decode(),
+            ReaderSource::Mmap(reader) => find_record(reader, ip, release_gvl)?.decode_path(path),
+            ReaderSource::Memory(reader) => find_record(reader, ip, release_gvl)?.decode_path(path),
         }
     }
 
@@ -419,36 +772,53 @@ impl ReaderSource {
     fn lookup_prefix(
         &self,
         ip: IpAddr,
+        release_gvl: bool,
+        cache: Option<&CacheHandle<'_>>,
     ) -> Result<(Option<RubyDecodedValue>, usize), maxminddb_crate::MaxMindDbError> {
-        let (result, prefix_len) = match self {
+        let (result, network) = self.lookup_network(ip, release_gvl, cache)?;
+        Ok((result, network_for_ip(ip, network).prefix() as usize))
+    }
+
+    /// Look up `ip`, returning the decoded record and the network it was found in
+    #[inline]
+    fn lookup_network(
+        &self,
+        ip: IpAddr,
+        release_gvl: bool,
+        cache: Option<&CacheHandle<'_>>,
+    ) -> Result<(Option<RubyDecodedValue>, IpNetwork), maxminddb_crate::MaxMindDbError> {
+        match self {
             ReaderSource::Mmap(reader) => {
-                let result = reader.lookup(ip)?;
+                let result = find_record(reader, ip, release_gvl)?;
                 let network = result.network()?;
-                let prefix = network.prefix();
-
-                let prefix_len = if ip.is_ipv4() && network.is_ipv6() {
-                    0
-                } else {
-                    prefix as usize
-                };
-
-                (result.decode()?, prefix_len)
+                Ok((decode_record(&result, cache)?, network))
             }
             ReaderSource::Memory(reader) => {
-                let result = reader.lookup(ip)?;
+                let result = find_record(reader, ip, release_gvl)?;
                 let network = result.network()?;
-                let prefix = network.prefix();
-
-                let prefix_len = if ip.is_ipv4() && network.is_ipv6() {
-                    0
-                } else {
-                    prefix as usize
-                };
+                Ok((decode_record(&result, cache)?, network))
+            }
+        }
+    }
 
-                (result.decode()?, prefix_len)
+    /// Look up `ip`, returning its record serialized as JSON and the network it was
+    /// found in
+    #[inline]
+    fn lookup_json(
+        &self,
+        ip: IpAddr,
+        release_gvl: bool,
+    ) -> Result<(Option<JsonRecord>, IpNetwork), maxminddb_crate::MaxMindDbError> {
+        match self {
+            ReaderSource::Mmap(reader) => {
+                let result = find_record(reader, ip, release_gvl)?;
+                Ok((result.decode()?, result.network()?))
             }
-        };
-        Ok((result, prefix_len))
+            ReaderSource::Memory(reader) => {
+                let result = find_record(reader, ip, release_gvl)?;
+                Ok((result.decode()?, result.network()?))
+            }
+        }
     }
 
     #[inline]
@@ -460,10 +830,14 @@ impl ReaderSource {
     }
 
     #[inline]
-    fn within(&self, network: IpNetwork) -> Result<ReaderWithin, MaxMindDbError> {
+    fn within(
+        &self,
+        network: IpNetwork,
+        options: WithinOptions,
+    ) -> Result<ReaderWithin, MaxMindDbError> {
         match self {
             ReaderSource::Mmap(reader) => {
-                let iter = reader.within(network, Default::default())?;
+                let iter = reader.within(network, options)?;
                 // SAFETY: the iterator holds a reference into `reader`. We'll store an Arc guard
                 // alongside it so the reader outlives the transmuted iterator.
                 Ok(ReaderWithin::Mmap(unsafe {
@@ -471,445 +845,2631 @@ impl ReaderSource {
                 }))
             }
             ReaderSource::Memory(reader) => {
-                let iter = reader.within(network, Default::default())?;
+                let iter = reader.within(network, options)?;
                 // SAFETY: same as above, the Arc guard keeps the reader alive.
                 Ok(ReaderWithin::Memory(unsafe {
-                    std::mem::transmute::<Within<'_, Vec<u8>>, Within<'static, Vec<u8>>>(iter)
+                    std::mem::transmute::<Within<'_, MemorySource>, Within<'static, MemorySource>>(
+                        iter,
+                    )
                 }))
             }
         }
     }
-}
 
-/// Wrapper enum for Within iterators
-enum ReaderWithin {
-    Mmap(Within<'static, Mmap>),
-    Memory(Within<'static, Vec<u8>>),
-}
+    /// Whether this IPv6 database points the ranges writers alias to IPv4 back at its
+    /// IPv4 networks. A range is aliased when it has no networks of its own but
+    /// following aliases finds some, and it must not hold networks of its own, which
+    /// a Writer with aliases couldn't store.
+    fn aliases_ipv4(&self) -> Result<bool, MaxMindDbError> {
+        if self.metadata().ip_version != 6 {
+            return Ok(false);
+        }
+        let mut aliased = false;
+        for (address, prefix) in writer::IPV4_ALIASES {
+            let range = IpNetwork::V6(
+                Ipv6Network::new(Ipv6Addr::from(address), prefix)
+                    .expect("alias prefix should be at most 128"),
+            );
+            if self
+                .within(range, WithinOptions::default())?
+                .next_as::<IgnoredAny>()
+                .transpose()?
+                .is_some()
+            {
+                return Ok(false);
+            }
+            let options = WithinOptions::default().include_aliased_networks();
+            aliased |= self
+                .within(range, options)?
+                .next_as::<IgnoredAny>()
+                .transpose()?
+                .is_some();
+        }
+        Ok(aliased)
+    }
 
-impl ReaderWithin {
-    fn next(&mut self) -> Option<Result<(IpNetwork, RubyDecodedValue), MaxMindDbError>> {
+    /// Walk the search tree for `ip`, keeping the result so its record can be
+    /// decoded later straight from its data section offset
+    #[inline]
+    fn record(&self, ip: IpAddr, release_gvl: bool) -> Result<ReaderRecord, MaxMindDbError> {
         match self {
-            ReaderWithin::Mmap(iter) => loop {
-                match iter.next() {
-                    None => return None,
-                    Some(Err(e)) => return Some(Err(e)),
-                    Some(Ok(lookup_result)) => {
-                        let network = match lookup_result.network() {
-                            Ok(n) => n,
-                            Err(e) => return Some(Err(e)),
-                        };
-                        match lookup_result.decode::<RubyDecodedValue>() {
-                            Ok(Some(data)) => return Some(Ok((network, data))),
-                            Ok(None) => continue, // Skip networks without data
-                            Err(e) => return Some(Err(e)),
-                        }
-                    }
-                }
-            },
-            ReaderWithin::Memory(iter) => loop {
-                match iter.next() {
-                    None => return None,
-                    Some(Err(e)) => return Some(Err(e)),
-                    Some(Ok(lookup_result)) => {
-                        let network = match lookup_result.network() {
-                            Ok(n) => n,
-                            Err(e) => return Some(Err(e)),
-                        };
-                        match lookup_result.decode::<RubyDecodedValue>() {
-                            Ok(Some(data)) => return Some(Ok((network, data))),
-                            Ok(None) => continue, // Skip networks without data
-                            Err(e) => return Some(Err(e)),
-                        }
-                    }
-                }
-            },
+            ReaderSource::Mmap(reader) => {
+                let result = find_record(reader, ip, release_gvl)?;
+                // SAFETY: the result holds a reference into `reader`. Holders keep an Arc
+                // to the source alongside it so the reader outlives the transmuted result.
+                Ok(ReaderRecord::Mmap(unsafe {
+                    std::mem::transmute::<LookupResult<'_, Mmap>, LookupResult<'static, Mmap>>(
+                        result,
+                    )
+                }))
+            }
+            ReaderSource::Memory(reader) => {
+                let result = find_record(reader, ip, release_gvl)?;
+                // SAFETY: same as above, the Arc guard keeps the reader alive.
+                Ok(ReaderRecord::Memory(unsafe {
+                    std::mem::transmute::<
+                        LookupResult<'_, MemorySource>,
+                        LookupResult<'static, MemorySource>,
+                    >(result)
+                }))
+            }
         }
     }
 }
 
-/// Metadata about the MaxMind DB database
-#[derive(Clone)]
-#[magnus::wrap(class = "MaxMind::DB::Rust::Metadata")]
-struct Metadata {
-    /// The major version number of the binary format used when creating the database.
-    binary_format_major_version: u16,
-    /// The minor version number of the binary format used when creating the database.
-    binary_format_minor_version: u16,
-    /// The Unix epoch timestamp for when the database was built.
-    build_epoch: u64,
-    /// A string identifying the database type (e.g., 'GeoIP2-City', 'GeoLite2-Country').
-    database_type: String,
-    description_map: BTreeMap<String, String>,
-    /// The IP version of the data in a database. A value of 4 means IPv4 only; 6 supports both IPv4 and IPv6.
-    ip_version: u16,
-    languages_list: Vec<String>,
-    /// The number of nodes in the search tree.
-    node_count: u32,
-    /// The record size in bits (24, 28, or 32).
-    record_size: u16,
+/// Walk the search tree for `ip`, optionally without holding the GVL.
+///
+/// The tree walk only reads the database buffer, so when `release_gvl` is set other
+/// Ruby threads can run while it page-faults through a large memory-mapped file.
+/// Decoding the returned result into Ruby objects must happen with the GVL held.
+#[inline]
+fn find_record<S: AsRef<[u8]>>(
+    reader: &MaxMindReader<S>,
+    ip: IpAddr,
+    release_gvl: bool,
+) -> Result<LookupResult<'_, S>, MaxMindDbError> {
+    if release_gvl {
+        without_gvl(|| reader.lookup(ip))
+    } else {
+        reader.lookup(ip)
+    }
 }
 
-impl Metadata {
-    fn binary_format_major_version(&self) -> u16 {
-        self.binary_format_major_version
+/// Decode the record for a lookup result, going through the record cache if one is given
+#[inline]
+fn decode_record<S: AsRef<[u8]>>(
+    result: &LookupResult<'_, S>,
+    cache: Option<&CacheHandle<'_>>,
+) -> Result<Option<RubyDecodedValue>, MaxMindDbError> {
+    let Some(cache) = cache else {
+        return result.decode();
+    };
+    let Some(offset) = result.offset() else {
+        return Ok(None);
+    };
+    if let Some(value) = cache.get(offset) {
+        return Ok(Some(value));
     }
-
-    fn binary_format_minor_version(&self) -> u16 {
-        self.binary_format_minor_version
+    let value = result.decode::<RubyDecodedValue>()?;
+    if let Some(value) = &value {
+        cache.insert(offset, value);
     }
+    Ok(value)
+}
 
-    fn build_epoch(&self) -> u64 {
-        self.build_epoch
+/// Run `func` with the GVL released.
+///
+/// `func` must not create or touch any Ruby objects. Panics are caught and resumed
+/// once the GVL has been reacquired so they never unwind across the C frame.
+fn without_gvl<F, R>(func: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    struct Call<F, R> {
+        func: Option<F>,
+        result: Option<std::thread::Result<R>>,
     }
 
-    fn database_type(&self) -> String {
-        self.database_type.clone()
+    unsafe extern "C" fn trampoline<F, R>(data: *mut c_void) -> *mut c_void
+    where
+        F: FnOnce() -> R,
+    {
+        // SAFETY: `data` points at the `Call` on the stack of `without_gvl`, which
+        // blocks until this function returns.
+        let call = unsafe { &mut *(data as *mut Call<F, R>) };
+        if let Some(func) = call.func.take() {
+            call.result = Some(panic::catch_unwind(AssertUnwindSafe(func)));
+        }
+        ptr::null_mut()
     }
 
-    fn description(&self) -> RHash {
-        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
-        let hash = ruby.hash_new();
-        for (k, v) in &self.description_map {
-            let _ = hash.aset(k.as_str(), v.as_str());
-        }
-        hash
+    let mut call = Call {
+        func: Some(func),
+        result: None,
+    };
+    // SAFETY: no unblocking function is needed since `func` never blocks indefinitely.
+    unsafe {
+        rb_sys::rb_thread_call_without_gvl(
+            Some(trampoline::<F, R>),
+            &mut call as *mut Call<F, R> as *mut c_void,
+            None,
+            ptr::null_mut(),
+        );
     }
 
-    fn ip_version(&self) -> u16 {
-        self.ip_version
+    match call
+        .result
+        .expect("function should run while the GVL is released")
+    {
+        Ok(result) => result,
+        Err(payload) => panic::resume_unwind(payload),
     }
+}
 
-    fn languages(&self) -> Vec<String> {
-        self.languages_list.clone()
+/// Wrapper enum for lookup results kept past the lookup that produced them
+enum ReaderRecord {
+    Mmap(LookupResult<'static, Mmap>),
+    Memory(LookupResult<'static, MemorySource>),
+}
+
+impl ReaderRecord {
+    #[inline]
+    fn network(&self) -> Result<IpNetwork, MaxMindDbError> {
+        match self {
+            ReaderRecord::Mmap(result) => result.network(),
+            ReaderRecord::Memory(result) => result.network(),
+        }
     }
 
-    fn node_count(&self) -> u32 {
-        self.node_count
+    #[inline]
+    fn offset(&self) -> Option<usize> {
+        match self {
+            ReaderRecord::Mmap(result) => result.offset(),
+            ReaderRecord::Memory(result) => result.offset(),
+        }
     }
 
-    fn record_size(&self) -> u16 {
-        self.record_size
+    #[inline]
+    fn decode_path(
+        &self,
+        path: &[PathElement<'_>],
+    ) -> Result<Option<RubyDecodedValue>, MaxMindDbError> {
+        match self {
+            ReaderRecord::Mmap(result) => result.decode_path(path),
+            ReaderRecord::Memory(result) => result.decode_path(path),
+        }
     }
+}
 
-    fn node_byte_size(&self) -> u16 {
-        self.record_size / 4
+/// Wrapper enum for Within iterators
+enum ReaderWithin {
+    Mmap(Within<'static, Mmap>),
+    Memory(Within<'static, MemorySource>),
+}
+
+impl ReaderWithin {
+    /// Advance to the next network, decoding its record. The record is None for
+    /// networks without data, which are only produced with
+    /// `WithinOptions::include_networks_without_data`.
+    fn next(&mut self) -> Option<Result<(IpNetwork, Option<RubyDecodedValue>), MaxMindDbError>> {
+        self.next_as()
     }
 
-    fn search_tree_size(&self) -> u32 {
-        self.node_count * (self.record_size as u32 / 4)
+    /// Like `next`, decoding the record as `T` instead of as a Ruby object
+    fn next_as<T: DeserializeOwned>(
+        &mut self,
+    ) -> Option<Result<(IpNetwork, Option<T>), MaxMindDbError>> {
+        match self {
+            ReaderWithin::Mmap(iter) => next_network(iter),
+            ReaderWithin::Memory(iter) => next_network(iter),
+        }
+    }
+}
+
+#[inline]
+fn next_network<S: AsRef<[u8]>, T: DeserializeOwned>(
+    iter: &mut Within<'static, S>,
+) -> Option<Result<(IpNetwork, Option<T>), MaxMindDbError>> {
+    let result = iter.next()?;
+    Some(result.and_then(|lookup_result| Ok((lookup_result.network()?, lookup_result.decode()?))))
+}
+
+/// Bounded cache of decoded records keyed by their offset in the data section.
+///
+/// Many networks share a record (e.g. every network in the same city), so repeated
+/// hits return the same frozen Ruby object instead of decoding it again. Entries are
+/// tied to the `ReaderSource` they were decoded from and dropped once it changes.
+///
+/// The cached values are only kept alive by `Reader`'s mark function, which takes
+/// the state lock. To keep that from deadlocking, no Ruby object may be allocated
+/// (which could trigger GC) while the lock is held.
+struct RecordCache {
+    capacity: usize,
+    state: Mutex<RecordCacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct RecordCacheState {
+    /// The database the cached offsets point into
+    source: Weak<ReaderSource>,
+    entries: HashMap<usize, Opaque<Value>>,
+    /// Cached offsets from oldest to newest, for eviction
+    order: VecDeque<usize>,
+}
+
+impl RecordCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(RecordCacheState {
+                source: Weak::new(),
+                entries: HashMap::with_capacity(capacity),
+                order: VecDeque::with_capacity(capacity),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, RecordCacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn get(&self, source: &Arc<ReaderSource>, offset: usize) -> Option<Opaque<Value>> {
+        let mut state = self.lock();
+        state.sync_source(source);
+        let value = state.entries.get(&offset).copied();
+        drop(state);
+
+        match value {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        value
+    }
+
+    fn insert(&self, source: &Arc<ReaderSource>, offset: usize, value: Opaque<Value>) {
+        let mut state = self.lock();
+        state.sync_source(source);
+        if state.entries.insert(offset, value).is_some() {
+            return;
+        }
+        state.order.push_back(offset);
+        while state.order.len() > self.capacity {
+            if let Some(oldest) = state.order.pop_front() {
+                state.entries.remove(&oldest);
+            }
+        }
+    }
+
+    fn clear(&self) {
+        let mut state = self.lock();
+        state.source = Weak::new();
+        state.entries.clear();
+        state.order.clear();
+    }
+
+    fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    fn mark(&self, ruby: &magnus::Ruby, marker: &Marker) {
+        for value in self.lock().entries.values() {
+            marker.mark(ruby.get_inner(*value));
+        }
+    }
+}
+
+impl RecordCacheState {
+    /// Drop all entries if they were decoded from a different database than `source`
+    #[inline]
+    fn sync_source(&mut self, source: &Arc<ReaderSource>) {
+        if !ptr::eq(self.source.as_ptr(), Arc::as_ptr(source)) {
+            self.source = Arc::downgrade(source);
+            self.entries.clear();
+            self.order.clear();
+        }
+    }
+}
+
+/// A record cache together with the database a lookup is running against
+struct CacheHandle<'a> {
+    ruby: &'a magnus::Ruby,
+    cache: &'a RecordCache,
+    source: &'a Arc<ReaderSource>,
+}
+
+impl CacheHandle<'_> {
+    #[inline]
+    fn get(&self, offset: usize) -> Option<RubyDecodedValue> {
+        self.cache
+            .get(self.source, offset)
+            .map(|value| RubyDecodedValue::new(self.ruby.get_inner(value)))
+    }
+
+    #[inline]
+    fn insert(&self, offset: usize, value: &RubyDecodedValue) {
+        self.cache
+            .insert(self.source, offset, Opaque::from(value.value));
+    }
+}
+
+/// Metadata about the MaxMind DB database
+#[derive(Clone)]
+#[magnus::wrap(class = "MaxMind::DB::Rust::Metadata")]
+struct Metadata {
+    /// The major version number of the binary format used when creating the database.
+    binary_format_major_version: u16,
+    /// The minor version number of the binary format used when creating the database.
+    binary_format_minor_version: u16,
+    /// The Unix epoch timestamp for when the database was built.
+    build_epoch: u64,
+    /// A string identifying the database type (e.g., 'GeoIP2-City', 'GeoLite2-Country').
+    database_type: String,
+    description_map: BTreeMap<String, String>,
+    /// The IP version of the data in a database. A value of 4 means IPv4 only; 6 supports both IPv4 and IPv6.
+    ip_version: u16,
+    languages_list: Vec<String>,
+    /// The number of nodes in the search tree.
+    node_count: u32,
+    /// The record size in bits (24, 28, or 32).
+    record_size: u16,
+}
+
+impl Metadata {
+    fn binary_format_major_version(&self) -> u16 {
+        self.binary_format_major_version
+    }
+
+    fn binary_format_minor_version(&self) -> u16 {
+        self.binary_format_minor_version
+    }
+
+    fn build_epoch(&self) -> u64 {
+        self.build_epoch
+    }
+
+    fn database_type(&self) -> String {
+        self.database_type.clone()
+    }
+
+    fn description(&self) -> RHash {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        let hash = ruby.hash_new();
+        for (k, v) in &self.description_map {
+            let _ = hash.aset(k.as_str(), v.as_str());
+        }
+        hash
+    }
+
+    fn ip_version(&self) -> u16 {
+        self.ip_version
+    }
+
+    fn languages(&self) -> Vec<String> {
+        self.languages_list.clone()
+    }
+
+    fn node_count(&self) -> u32 {
+        self.node_count
+    }
+
+    fn record_size(&self) -> u16 {
+        self.record_size
+    }
+
+    fn node_byte_size(&self) -> u16 {
+        self.record_size / 4
+    }
+
+    fn search_tree_size(&self) -> u32 {
+        self.node_count * (self.record_size as u32 / 4)
     }
 }
 
 unsafe impl Send for Metadata {}
 
-/// A Ruby wrapper around the MaxMind DB reader
-#[derive(Clone)]
-#[magnus::wrap(class = "MaxMind::DB::Rust::Reader")]
-struct Reader {
-    reader: Arc<ArcSwapOption<ReaderSource>>,
-    closed: Arc<AtomicBool>,
-    ip_version: u16,
-}
+/// The outcome of `Reader#lookup`: where an address landed in the search tree,
+/// with its record decoded only when `data` is first called
+#[magnus::wrap(class = "MaxMind::DB::Rust::LookupResult", mark)]
+struct ReaderLookupResult {
+    /// The search tree result, decoded from its data section offset by `data`.
+    /// Declared before `source` so it is dropped first.
+    record: ReaderRecord,
+    /// Keeps the database alive so `data` works even after the reader is closed
+    /// or reloaded
+    _source: Arc<ReaderSource>,
+    ip: IpAddr,
+    network: IpNetwork,
+    data_offset: Option<usize>,
+    decode: DecodeOptions,
+    /// The decoded record, once `data` has been called
+    data: OnceCell<Opaque<Value>>,
+}
+
+impl DataTypeFunctions for ReaderLookupResult {
+    fn mark(&self, marker: &Marker) {
+        if let Some(data) = self.data.get() {
+            let ruby = magnus::Ruby::get().expect("Ruby VM should be available during GC");
+            marker.mark(ruby.get_inner(*data));
+        }
+    }
+}
+
+impl ReaderLookupResult {
+    fn found(&self) -> bool {
+        self.data_offset.is_some()
+    }
+
+    fn network(&self) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        network_to_ipaddr(&ruby, self.ip, self.network)
+    }
+
+    fn prefix_length(&self) -> u8 {
+        network_for_ip(self.ip, self.network).prefix()
+    }
+
+    fn data_offset(&self) -> Option<usize> {
+        self.data_offset
+    }
+
+    fn data(&self) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        if let Some(data) = self.data.get() {
+            return Ok(ruby.get_inner(*data));
+        }
+        if self.data_offset.is_none() {
+            return Ok(ruby.qnil().as_value());
+        }
+        let data = with_decode_options(&self.decode, || self.record.decode_path(&[]))
+            .map_err(|e| lookup_error(&ruby, e))?;
+        let data = data.map_or_else(|| ruby.qnil().as_value(), |data| data.into_value());
+        Ok(ruby.get_inner(*self.data.get_or_init(|| Opaque::from(data))))
+    }
+}
+
+/// A record returned by `Reader#get_lazy`. Only the parts that are accessed are
+/// decoded into Ruby objects.
+#[magnus::wrap(class = "MaxMind::DB::Rust::Record")]
+struct LazyRecord {
+    /// The search tree result, decoded from its data section offset on each
+    /// access. Declared before `source` so it is dropped first.
+    record: ReaderRecord,
+    data_offset: usize,
+    /// Keeps the database alive after the reader is closed or reloaded
+    _source: Arc<ReaderSource>,
+    decode: DecodeOptions,
+}
+
+impl LazyRecord {
+    fn get(&self, ruby: &magnus::Ruby, path: &[PathSegment]) -> Result<Value, Error> {
+        let options = self.decode.for_path(path);
+        let path: Vec<PathElement<'_>> = path.iter().map(PathSegment::as_element).collect();
+
+        match with_decode_options(&options, || self.record.decode_path(&path)) {
+            Ok(Some(data)) => Ok(data.into_value()),
+            Ok(None) => Ok(ruby.qnil().as_value()),
+            Err(e) => Err(lookup_error(ruby, e)),
+        }
+    }
+
+    fn aref(&self, key: Value) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        self.get(&ruby, &[parse_path_segment(&ruby, key)?])
+    }
+
+    fn dig(&self, args: &[Value]) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(), (), RArray, (), (), ()>(args)?;
+        let path = parse_path(&ruby, args.splat)?;
+        if path.is_empty() {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "wrong number of arguments (given 0, expected 1+)",
+            ));
+        }
+        self.get(&ruby, &path)
+    }
+
+    /// Like `Hash#fetch`: return the value for `key`, or else the default or the
+    /// block's result, raising KeyError if neither is given
+    fn fetch(&self, args: &[Value]) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(Value,), (Option<Value>,), (), (), (), ()>(args)?;
+        let (key,) = args.required;
+        let (default,) = args.optional;
+
+        let value = self.get(&ruby, &[parse_path_segment(&ruby, key)?])?;
+        if !value.is_nil() {
+            return Ok(value);
+        }
+        if ruby.block_given() {
+            return ruby.yield_value(key);
+        }
+        default.ok_or_else(|| {
+            Error::new(
+                ruby.exception_key_error(),
+                format!("key not found: {}", key.inspect()),
+            )
+        })
+    }
+
+    fn to_h(&self) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        self.get(&ruby, &[])
+    }
+
+    fn each(rb_self: Obj<Self>) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("each", ()).as_value());
+        }
+        let record = rb_self.to_h()?;
+        record.funcall_with_block::<_, _, Value>("each", (), ruby.block_proc()?)?;
+        Ok(rb_self.as_value())
+    }
+
+    fn data_offset(&self) -> usize {
+        self.data_offset
+    }
+}
+
+/// Resumable iteration over the networks in a database, returned by `Reader#cursor`
+#[magnus::wrap(class = "MaxMind::DB::Rust::Cursor")]
+struct Cursor {
+    /// Declared before `source` so the iterator borrowing it is dropped first
+    state: Mutex<CursorState>,
+    source: Arc<ReaderSource>,
+    decode: DecodeOptions,
+}
+
+struct CursorState {
+    /// Iterator over the block currently being walked
+    iter: Option<ReaderWithin>,
+    /// Blocks still to be walked, in address order
+    pending: VecDeque<IpNetwork>,
+    /// The last network returned, or the `start_after` network
+    position: Option<IpNetwork>,
+}
+
+impl Cursor {
+    /// Advance to the next network with data.
+    ///
+    /// The state lock is held only while walking and decoding, which never calls
+    /// back into Ruby code, so another thread can't block on it while holding the
+    /// GVL. Concurrent use from a thread that released the GVL is reported rather
+    /// than waited for.
+    fn advance(&self, ruby: &magnus::Ruby) -> Result<Option<(IpNetwork, Value)>, Error> {
+        let Ok(mut state) = self.state.try_lock() else {
+            return Err(Error::new(
+                ruby.exception_runtime_error(),
+                "Cursor is in use by another thread",
+            ));
+        };
+        let state = &mut *state;
+        loop {
+            if let Some(iter) = &mut state.iter {
+                match with_decode_options(&self.decode, || iter.next()) {
+                    Some(Ok((network, Some(data)))) => {
+                        state.position = Some(network);
+                        return Ok(Some((network, data.into_value())));
+                    }
+                    Some(Ok((_, None))) => continue,
+                    Some(Err(e)) => return Err(iteration_error(ruby, e)),
+                    None => state.iter = None,
+                }
+            }
+            let Some(block) = state.pending.pop_front() else {
+                return Ok(None);
+            };
+            let iter = self
+                .source
+                .within(block, WithinOptions::default())
+                .map_err(|e| iteration_error(ruby, e))?;
+            state.iter = Some(iter);
+        }
+    }
+
+    fn pair(ruby: &magnus::Ruby, network: IpNetwork, data: Value) -> Result<RArray, Error> {
+        let ipaddr_class = ruby.class_object().const_get::<_, RClass>("IPAddr")?;
+        let ipaddr = ipaddr_class.funcall::<_, _, Value>("new", (network.to_string(),))?;
+        let pair = ruby.ary_new_capa(2);
+        pair.push(ipaddr)?;
+        pair.push(data)?;
+        Ok(pair)
+    }
+
+    /// Return the next `[network, data]` pair, or nil when iteration is finished
+    fn next(&self) -> Result<Option<RArray>, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        match self.advance(&ruby)? {
+            Some((network, data)) => Ok(Some(Self::pair(&ruby, network, data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Return up to `count` `[network, data]` pairs, or an empty Array when finished
+    fn next_batch(&self, count: usize) -> Result<RArray, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        let batch = ruby.ary_new_capa(count.min(1024));
+        for _ in 0..count {
+            let Some((network, data)) = self.advance(&ruby)? else {
+                break;
+            };
+            batch.push(Self::pair(&ruby, network, data)?)?;
+        }
+        Ok(batch)
+    }
+
+    /// Yield each remaining `[network, data]` pair
+    fn each(rb_self: Obj<Self>) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("each", ()).as_value());
+        }
+        while let Some((network, data)) = rb_self.advance(&ruby)? {
+            ruby.yield_value::<_, Value>(Self::pair(&ruby, network, data)?)?;
+        }
+        Ok(rb_self.as_value())
+    }
+
+    /// The last network returned as a CIDR String, for passing to `start_after:`
+    fn position(&self) -> Option<String> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.position.map(|network| network.to_string())
+    }
+}
+
+/// A Ruby wrapper around the MaxMind DB reader
+#[derive(Clone)]
+#[magnus::wrap(class = "MaxMind::DB::Rust::Reader", mark)]
+struct Reader {
+    reader: Arc<ArcSwapOption<ReaderSource>>,
+    closed: Arc<AtomicBool>,
+    /// IP version of the current database, updated by `reload!`
+    ip_version: Arc<AtomicU16>,
+    origin: Arc<Mutex<DatabaseOrigin>>,
+    /// Whether to release the GVL while walking the search tree
+    release_gvl: bool,
+    /// Default options for converting records into Ruby objects
+    decode: DecodeOptions,
+    /// Cache of records decoded with the default options, if enabled
+    cache: Option<Arc<RecordCache>>,
+    /// File change detection, if `auto_reload: true`
+    auto_reload: Option<Arc<AutoReload>>,
+}
+
+impl DataTypeFunctions for Reader {
+    fn mark(&self, marker: &Marker) {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available during GC");
+        if let Some(cache) = &self.cache {
+            cache.mark(&ruby, marker);
+        }
+        if let Some(auto_reload) = &self.auto_reload {
+            auto_reload.mark(&ruby, marker);
+        }
+    }
+}
+
+impl Reader {
+    fn new(args: &[Value]) -> Result<Self, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(String,), (), (), (), _, ()>(args)?;
+        let (database,) = args.required;
+        let options = ReaderOptions::parse(args.keywords)?;
+
+        // Parse mode from options hash
+        let mode = match options.mode {
+            Some(mode) => OpenMode::from_symbol(&ruby, mode)?,
+            None => OpenMode::Mmap,
+        };
+
+        // Fingerprint the file before opening it, so a replacement that lands in
+        // between is picked up by the first check rather than missed
+        let auto_reload = options.auto_reload(&ruby, &database)?;
+
+        // Open database with appropriate mode
+        let member = options.member.clone();
+        let source = open_database(&database, mode, member.as_deref())?;
+        let mut reader = create_reader(
+            source,
+            DatabaseOrigin {
+                path: Some(database),
+                mode,
+                member,
+            },
+        );
+        reader.auto_reload = auto_reload.map(Arc::new);
+        options.configure(&ruby, &mut reader)?;
+        Ok(reader)
+    }
+
+    /// Open a database held in a Ruby String. Frozen Strings are used in place
+    /// without copying; other Strings are copied so later changes can't affect
+    /// the reader.
+    fn from_bytes(args: &[Value]) -> Result<Self, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(RString,), (), (), (), _, ()>(args)?;
+        let (bytes,) = args.required;
+        let options = ReaderOptions::parse(args.keywords)?;
+        options.ensure_no_file_options(&ruby)?;
+
+        let buffer = if bytes.is_frozen() {
+            MemorySource::Ruby(RubyBytes::new(bytes))
+        } else {
+            // SAFETY: the slice is copied before any Ruby code can run
+            MemorySource::Owned(unsafe { bytes.as_slice() }.to_vec())
+        };
+        let source = open_memory_source(buffer, options.member.as_deref(), "database from bytes")?;
+        let mut reader = create_reader(source, DatabaseOrigin::in_memory());
+        options.configure(&ruby, &mut reader)?;
+        Ok(reader)
+    }
+
+    /// Open a database by reading all of `io`, which must respond to `read`
+    fn from_io(args: &[Value]) -> Result<Self, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(Value,), (), (), (), _, ()>(args)?;
+        let (io,) = args.required;
+        let options = ReaderOptions::parse(args.keywords)?;
+        options.ensure_no_file_options(&ruby)?;
+
+        let buffer = match io.funcall::<_, _, Option<RString>>("read", ())? {
+            // SAFETY: the slice is copied before any Ruby code can run
+            Some(bytes) => unsafe { bytes.as_slice() }.to_vec(),
+            None => Vec::new(),
+        };
+        let source = open_memory_source(
+            MemorySource::Owned(buffer),
+            options.member.as_deref(),
+            "database from IO",
+        )?;
+        let mut reader = create_reader(source, DatabaseOrigin::in_memory());
+        options.configure(&ruby, &mut reader)?;
+        Ok(reader)
+    }
+
+    #[inline]
+    fn get(&self, args: &[Value]) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        // Avoid scan_args on the hot single-argument path
+        let (ip_address, path, options) = if let [ip_address] = *args {
+            (ip_address, None, None)
+        } else {
+            let args = scan_args::<(Value,), (Option<Option<RArray>>,), (), (), RHash, ()>(args)?;
+            let (ip_address,) = args.required;
+            let (path,) = args.optional;
+            let options = self.call_decode_options(&ruby, args.keywords)?;
+            (ip_address, path.flatten(), options)
+        };
+        let options = options.as_ref().unwrap_or(&self.decode);
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        match path {
+            None => self.lookup_value(&ruby, reader, ip_address, options),
+            Some(path) => {
+                let path = parse_path(&ruby, path)?;
+                self.lookup_path_value(&ruby, reader, ip_address, &path, options)
+            }
+        }
+    }
+
+    /// Look up an IP address and decode only the value at `path`.
+    ///
+    /// Path segments are map keys (String or Symbol) or array indexes (Integer, negative
+    /// values count from the end). Only the value at the end of the path is converted to
+    /// Ruby objects. Returns nil if the address has no data or any segment is missing.
+    fn dig(&self, args: &[Value]) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(Value,), (), RArray, (), (), ()>(args)?;
+        let (ip_address,) = args.required;
+        let path = parse_path(&ruby, args.splat)?;
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        self.lookup_path_value(&ruby, reader, ip_address, &path, &self.decode)
+    }
+
+    #[inline]
+    fn get_with_prefix_length(&self, ip_address: Value) -> Result<RArray, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        self.lookup_value_with_prefix(&ruby, reader, ip_address, &self.decode)
+    }
+
+    /// Look up `ip_address`, returning `[record, network]` where `network` is an
+    /// IPAddr for the network the record was found in. IPv4 lookups in IPv6
+    /// databases return IPv4 networks.
+    fn get_with_network(&self, ip_address: Value) -> Result<RArray, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        let parsed_ip = self.parse_lookup_ip(&ruby, ip_address)?;
+        let cache = self.cache_handle(&ruby, reader, &self.decode);
+        let (data, network) = with_decode_options(&self.decode, || {
+            reader.lookup_network(parsed_ip, self.release_gvl, cache.as_ref())
+        })
+        .map_err(|e| lookup_error(&ruby, e))?;
+
+        let ipaddr = network_to_ipaddr(&ruby, parsed_ip, network)?;
+
+        let arr = ruby.ary_new_capa(2);
+        match data {
+            Some(data) => arr.push(data.into_value())?,
+            None => arr.push(ruby.qnil().as_value())?,
+        }
+        arr.push(ipaddr)?;
+        Ok(arr)
+    }
+
+    /// Look up `ip_address` and return its record as a JSON String, serialized in Rust
+    /// without building a Ruby Hash. With `network: true`, the String is an object
+    /// holding the network and the record.
+    fn get_json(&self, args: &[Value]) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(Value,), (), (), (), _, ()>(args)?;
+        let (ip_address,) = args.required;
+        let kw = get_kwargs::<_, (), (Option<bool>,), ()>(args.keywords, &[], &["network"])?;
+        let (include_network,) = kw.optional;
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        self.lookup_json_value(&ruby, reader, ip_address, include_network.unwrap_or(false))
+    }
+
+    /// Batch form of `get_json`. Failed lookups raise, or store `nil` or the exception
+    /// object depending on `on_error:`.
+    fn get_many_json(&self, args: &[Value]) -> Result<RArray, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(RArray,), (), (), (), _, ()>(args)?;
+        let (ips,) = args.required;
+        let kw = get_kwargs::<_, (), (Option<bool>, Option<Symbol>), ()>(
+            args.keywords,
+            &[],
+            &["network", "on_error"],
+        )?;
+        let (include_network, on_error) = kw.optional;
+        let include_network = include_network.unwrap_or(false);
+        let on_error = BatchErrorPolicy::from_symbol(&ruby, on_error)?;
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        let results = ruby.ary_new_capa(ips.len());
+        for i in 0..ips.len() {
+            let ip_address: Value = ips.entry(i as isize)?;
+            let value = on_error.apply(
+                &ruby,
+                self.lookup_json_value(&ruby, reader, ip_address, include_network),
+            )?;
+            results.push(value)?;
+        }
+        Ok(results)
+    }
+
+    /// Look up `ip_address` without decoding its record, returning a LookupResult
+    /// that tells whether data was found, where, and decodes it on demand
+    fn lookup(&self, ip_address: Value) -> Result<ReaderLookupResult, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        let parsed_ip = self.parse_lookup_ip(&ruby, ip_address)?;
+        let record = reader
+            .record(parsed_ip, self.release_gvl)
+            .map_err(|e| lookup_error(&ruby, e))?;
+        let network = record.network().map_err(|e| lookup_error(&ruby, e))?;
+        let data_offset = record.offset();
+
+        Ok(ReaderLookupResult {
+            record,
+            _source: reader.clone(),
+            ip: parsed_ip,
+            network,
+            data_offset,
+            decode: self.decode.clone(),
+            data: OnceCell::new(),
+        })
+    }
+
+    /// Look up `ip_address`, returning a Record that decodes fields on access, or
+    /// nil if no data was found
+    fn get_lazy(&self, ip_address: Value) -> Result<Option<LazyRecord>, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        let parsed_ip = self.parse_lookup_ip(&ruby, ip_address)?;
+        let record = reader
+            .record(parsed_ip, self.release_gvl)
+            .map_err(|e| lookup_error(&ruby, e))?;
+        let Some(data_offset) = record.offset() else {
+            return Ok(None);
+        };
+
+        Ok(Some(LazyRecord {
+            record,
+            data_offset,
+            _source: reader.clone(),
+            decode: self.decode.clone(),
+        }))
+    }
+
+    /// Look up every IP address in `ips`, returning the records in the same order.
+    ///
+    /// The reader guard is loaded once for the whole batch. The `on_error:` keyword
+    /// controls what happens when an individual lookup fails: `:raise` (default)
+    /// raises immediately, `:nil` stores `nil` in that slot, and `:exception` stores
+    /// the exception object in that slot.
+    fn get_many(&self, args: &[Value]) -> Result<RArray, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let (ips, on_error) = parse_batch_args(&ruby, args)?;
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        let results = ruby.ary_new_capa(ips.len());
+        for i in 0..ips.len() {
+            let ip_address: Value = ips.entry(i as isize)?;
+            let value = on_error.apply(
+                &ruby,
+                self.lookup_value(&ruby, reader, ip_address, &self.decode),
+            )?;
+            results.push(value)?;
+        }
+        Ok(results)
+    }
+
+    /// Batch form of `get_with_prefix_length`. Each slot holds a `[record, prefix_length]`
+    /// pair, or `nil`/the exception object for failed lookups depending on `on_error:`.
+    fn get_many_with_prefix_length(&self, args: &[Value]) -> Result<RArray, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let (ips, on_error) = parse_batch_args(&ruby, args)?;
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        let results = ruby.ary_new_capa(ips.len());
+        for i in 0..ips.len() {
+            let ip_address: Value = ips.entry(i as isize)?;
+            let value = on_error.apply(
+                &ruby,
+                self.lookup_value_with_prefix(&ruby, reader, ip_address, &self.decode)
+                    .map(|arr| arr.as_value()),
+            )?;
+            results.push(value)?;
+        }
+        Ok(results)
+    }
+
+    fn metadata(&self) -> Result<Metadata, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+        let meta = reader.metadata();
+
+        Ok(Metadata {
+            binary_format_major_version: meta.binary_format_major_version,
+            binary_format_minor_version: meta.binary_format_minor_version,
+            build_epoch: meta.build_epoch,
+            database_type: meta.database_type.clone(),
+            description_map: meta.description.clone(),
+            ip_version: meta.ip_version,
+            languages_list: meta.languages.clone(),
+            node_count: meta.node_count,
+            record_size: meta.record_size,
+        })
+    }
+
+    fn close(&self) {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.reader.store(None);
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+    }
+
+    /// Return the record cache counters, or nil if the cache is disabled
+    fn cache_stats(&self) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let Some(cache) = &self.cache else {
+            return Ok(ruby.qnil().as_value());
+        };
+        let stats = ruby.hash_new_capa(4);
+        stats.aset(ruby.to_symbol("hits"), cache.hits.load(Ordering::Relaxed))?;
+        stats.aset(
+            ruby.to_symbol("misses"),
+            cache.misses.load(Ordering::Relaxed),
+        )?;
+        stats.aset(ruby.to_symbol("size"), cache.len())?;
+        stats.aset(ruby.to_symbol("capacity"), cache.capacity)?;
+        Ok(stats.as_value())
+    }
+
+    fn closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Reopen the database and atomically switch new lookups over to it.
+    ///
+    /// `path` and `mode:` default to the ones the database was last loaded with. The
+    /// new file is opened and validated before the swap, so on error the current
+    /// database stays in use. Lookups already in progress finish against the old
+    /// database, which is released once the last of them completes.
+    fn reload(rb_self: Obj<Self>, args: &[Value]) -> Result<Obj<Self>, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(), (Option<String>,), (), (), _, ()>(args)?;
+        let (path,) = args.optional;
+        let kw = get_kwargs::<_, (), (Option<Symbol>, Option<String>), ()>(
+            args.keywords,
+            &[],
+            &["mode", "member"],
+        )?;
+        let (mode, member) = kw.optional;
+
+        let (path, mode, member) = {
+            let origin = rb_self
+                .origin
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            // The archive member only carries over when reloading the same file
+            let (path, member) = match (path, &origin.path) {
+                (Some(path), _) => (path, member),
+                (None, Some(path)) => (path.clone(), member.or_else(|| origin.member.clone())),
+                (None, None) => {
+                    return Err(Error::new(
+                        ruby.exception_arg_error(),
+                        "reload! needs a path for a database that was not opened from a file",
+                    ))
+                }
+            };
+            let mode = match mode {
+                Some(mode) => OpenMode::from_symbol(&ruby, mode)?,
+                None => origin.mode,
+            };
+            (path, mode, member)
+        };
+
+        if rb_self.reader.load().is_none() {
+            return Err(Error::new(ruby.exception_runtime_error(), ERR_CLOSED_DB));
+        }
+        rb_self.reload_from(&ruby, path, mode, member)?;
+        Ok(rb_self)
+    }
+
+    /// Return the error from the most recent failed automatic reload, or nil
+    fn last_reload_error(&self) -> Option<Value> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        let auto_reload = self.auto_reload.as_ref()?;
+        let state = auto_reload.lock();
+        state.last_error.map(|error| ruby.get_inner(error))
+    }
+
+    /// Open `path` with `mode` and make it the current database
+    fn reload_from(
+        &self,
+        ruby: &magnus::Ruby,
+        path: String,
+        mode: OpenMode,
+        member: Option<String>,
+    ) -> Result<(), Error> {
+        let fingerprint = FileFingerprint::of(&path);
+        let source = open_database(&path, mode, member.as_deref())?;
+        self.swap_source(ruby, source)?;
+
+        if let Some(auto_reload) = &self.auto_reload {
+            let mut state = auto_reload.lock();
+            state.fingerprint = fingerprint;
+            state.last_error = None;
+        }
+        let mut origin = self.origin.lock().unwrap_or_else(PoisonError::into_inner);
+        *origin = DatabaseOrigin {
+            path: Some(path),
+            mode,
+            member,
+        };
+        Ok(())
+    }
+
+    /// Reload the database if `auto_reload` is enabled, the check interval has
+    /// passed, and the file on disk has changed since it was last loaded.
+    ///
+    /// A failed reload keeps the current database, is recorded as
+    /// `last_reload_error`, and is passed to the `on_reload_error` callback. The
+    /// same file is not retried until it changes again.
+    fn check_auto_reload(&self, ruby: &magnus::Ruby) -> Result<(), Error> {
+        let Some(auto_reload) = &self.auto_reload else {
+            return Ok(());
+        };
+        let Some(previous) = auto_reload.start_check() else {
+            return Ok(());
+        };
+        let (path, mode, member) = {
+            let origin = self.origin.lock().unwrap_or_else(PoisonError::into_inner);
+            match &origin.path {
+                Some(path) => (path.clone(), origin.mode, origin.member.clone()),
+                None => return Ok(()),
+            }
+        };
+        // A missing file is usually a replacement in progress; check again later
+        let Some(current) = FileFingerprint::of(&path) else {
+            return Ok(());
+        };
+        if Some(current) == previous {
+            return Ok(());
+        }
+
+        let Err(e) = self.reload_from(ruby, path, mode, member) else {
+            return Ok(());
+        };
+        // Non-local exits such as `throw` can't be stored; let them continue
+        let Some(error) = e.value() else {
+            return Err(e);
+        };
+        {
+            let mut state = auto_reload.lock();
+            state.fingerprint = Some(current);
+            state.last_error = Some(Opaque::from(error));
+        }
+        match auto_reload.on_error {
+            Some(callback) => ruby
+                .get_inner(callback)
+                .funcall::<_, _, Value>("call", (error,))
+                .map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Atomically replace the current database with `source`
+    fn swap_source(&self, ruby: &magnus::Ruby, source: ReaderSource) -> Result<(), Error> {
+        let ip_version = source.metadata().ip_version;
+        let source = Arc::new(source);
+
+        // Never resurrect a reader that was closed while the new file was loading
+        let previous = self
+            .reader
+            .rcu(|current| current.is_some().then(|| source.clone()));
+        if previous.is_none() {
+            return Err(Error::new(ruby.exception_runtime_error(), ERR_CLOSED_DB));
+        }
+        self.ip_version.store(ip_version, Ordering::Release);
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    /// Yield `network, data` for each network in the database, or within `network`.
+    ///
+    /// Without a block, returns an Enumerator that walks the tree only as far as it
+    /// is consumed, so `each.lazy`, `first(n)`, and external `next` stop early. Its
+    /// `size` is nil because counting networks requires a full traversal.
+    fn each(rb_self: Obj<Self>, args: &[Value]) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let parsed = scan_args::<(), (Option<Value>,), (), (), RHash, ()>(args)?;
+        let (network_arg,) = parsed.optional;
+        // get_kwargs removes the keywords it reads, so keep a copy for the Enumerator
+        let keywords: RHash = parsed.keywords.funcall("dup", ())?;
+        let kw = get_kwargs::<_, (), (Option<bool>, Option<bool>, Option<bool>), ()>(
+            parsed.keywords,
+            &[],
+            &["include_empty", "include_aliased", "skip_empty_values"],
+        )?;
+        let (include_empty, include_aliased, skip_empty_values) = kw.optional;
+
+        let mut options = WithinOptions::default();
+        if include_empty.unwrap_or(false) {
+            options = options.include_networks_without_data();
+        }
+        if include_aliased.unwrap_or(false) {
+            options = options.include_aliased_networks();
+        }
+        if skip_empty_values.unwrap_or(false) {
+            options = options.skip_empty_values();
+        }
+
+        let guard = rb_self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        // If no block given, return an enumerator that calls back into `each` with
+        // the same arguments, passing the options as keywords
+        if !ruby.block_given() {
+            let enumerator = match network_arg {
+                Some(network) => rb_self.enumeratorize("each", (network, KwArgs(keywords))),
+                None => rb_self.enumeratorize("each", (KwArgs(keywords),)),
+            };
+            return Ok(enumerator.as_value());
+        }
+
+        let ip_version = reader.metadata().ip_version;
+
+        // Determine the network to iterate over
+        let network = search_network(&ruby, network_arg, ip_version)?;
+
+        let mut iter = reader.within(network, options).map_err(|e| {
+            Error::new(
+                ExceptionClass::from_value(invalid_database_error().as_value())
+                    .expect("InvalidDatabaseError should convert to ExceptionClass"),
+                format!("Failed to iterate: {}", e),
+            )
+        })?;
+
+        // Get IPAddr class
+        let ipaddr_class = ruby.class_object().const_get::<_, RClass>("IPAddr")?;
+
+        // Iterate over all networks
+        while let Some(result) = with_decode_options(&rb_self.decode, || iter.next()) {
+            match result {
+                Ok((network, data)) => {
+                    // Convert IpNetwork to IPAddr
+                    let ip_str = network.to_string();
+                    let ipaddr = ipaddr_class.funcall::<_, _, Value>("new", (ip_str,))?;
+
+                    // Yield [network, data] to block, with nil data for empty networks
+                    let data = match data {
+                        Some(data) => data.into_value(),
+                        None => ruby.qnil().as_value(),
+                    };
+                    let values = (ipaddr, data);
+                    ruby.yield_values::<(Value, Value), Value>(values)?;
+                }
+                Err(e) => return Err(iteration_error(&ruby, e)),
+            }
+        }
+
+        Ok(ruby.qnil().as_value())
+    }
+
+    /// Write every network within `network` (default: the whole database) and its
+    /// record to `target`, a path or an IO, as JSON Lines or CSV. Records are
+    /// serialized in Rust as they are decoded, without creating Ruby objects.
+    /// Returns the number of networks written.
+    fn export(&self, args: &[Value]) -> Result<usize, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(Value,), (), (), (), _, ()>(args)?;
+        let (target,) = args.required;
+        let kw = get_kwargs::<_, (), (Option<Symbol>, Option<Value>, Option<Vec<String>>), ()>(
+            args.keywords,
+            &[],
+            &["format", "network", "columns"],
+        )?;
+        let (format, network_arg, columns) = kw.optional;
+
+        let format = ExportFormat::from_symbol(&ruby, format)?;
+        let columns: Vec<ExportColumn> = columns
+            .unwrap_or_default()
+            .into_iter()
+            .map(ExportColumn::parse)
+            .collect();
+        if format == ExportFormat::Csv && columns.is_empty() {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "CSV export requires columns",
+            ));
+        }
+        let exporter = Exporter { format, columns };
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+        let network = search_network(&ruby, network_arg, reader.metadata().ip_version)?;
+
+        let result = if target.respond_to("write", false)? {
+            let mut out = BufWriter::with_capacity(
+                EXPORT_BUFFER_SIZE,
+                RubyIoWriter {
+                    io: target,
+                    error: None,
+                },
+            );
+            let result = exporter.run(reader, network, &mut out);
+            // Drop anything left unwritten after a failure instead of retrying it
+            let (mut io, _) = out.into_parts();
+            if let Some(e) = io.error.take() {
+                return Err(e);
+            }
+            result
+        } else {
+            let path: String = if target.respond_to("to_path", false)? {
+                target.funcall("to_path", ())?
+            } else {
+                String::try_convert(target)?
+            };
+            let file = File::create(&path).map_err(|e| {
+                Error::new(
+                    ruby.exception_io_error(),
+                    format!("Failed to create {}: {}", path, e),
+                )
+            })?;
+            let mut out = BufWriter::with_capacity(EXPORT_BUFFER_SIZE, file);
+            if self.release_gvl {
+                without_gvl(|| exporter.run(reader, network, &mut out))
+            } else {
+                exporter.run(reader, network, &mut out)
+            }
+        };
+
+        result.map_err(|e| match e {
+            ExportError::Database(e) => iteration_error(&ruby, e),
+            ExportError::InvalidUtf8 => Error::new(
+                ExceptionClass::from_value(invalid_database_error().as_value())
+                    .expect("InvalidDatabaseError should convert to ExceptionClass"),
+                ERR_BAD_DATA,
+            ),
+            ExportError::Io(e) => Error::new(
+                ruby.exception_io_error(),
+                format!("Failed to write export: {}", e),
+            ),
+        })
+    }
 
-impl Reader {
-    fn new(args: &[Value]) -> Result<Self, Error> {
+    /// Write a new database to `path` with the networks within `network` (default:
+    /// the whole database) whose records `where:` accepts, keeping only the top-level
+    /// `fields:` of each record. The new database keeps this one's metadata apart from
+    /// its build epoch and `description:`, which defaults to this database's
+    /// description marked as a subset. Returns the sizes `Writer#write` returns.
+    fn write_subset(&self, args: &[Value]) -> Result<RHash, Error> {
         let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
 
         let args = scan_args::<(String,), (), (), (), _, ()>(args)?;
-        let (database,) = args.required;
-        let kw = get_kwargs::<_, (), (Option<Symbol>,), ()>(args.keywords, &[], &["mode"])?;
-        let (mode,) = kw.optional;
-
-        // Parse mode from options hash
-        let mode: Symbol = mode.unwrap_or_else(|| ruby.to_symbol("MODE_AUTO"));
-
-        let mode_str = mode.name()?;
-        let mode_str: &str = &mode_str;
-
-        // Determine actual mode to use
-        let actual_mode = match mode_str {
-            "MODE_AUTO" | "MODE_MMAP" => "MMAP",
-            "MODE_MEMORY" => "MEMORY",
-            _ => {
+        let (path,) = args.required;
+        let kw = get_kwargs::<
+            _,
+            (),
+            (
+                Option<Value>,
+                Option<Vec<String>>,
+                Option<Value>,
+                Option<RHash>,
+            ),
+            (),
+        >(
+            args.keywords,
+            &[],
+            &["network", "fields", "where", "description"],
+        )?;
+        let (network_arg, fields, filter, description) = kw.optional;
+        if let Some(filter) = filter {
+            if !filter.respond_to("call", false)? {
                 return Err(Error::new(
-                    ruby.exception_arg_error(),
-                    format!("Unsupported mode: {}", mode_str),
-                ))
+                    ruby.exception_type_error(),
+                    "where: must respond to call",
+                ));
+            }
+        }
+
+        // The Ruby block may reload or close this Reader, so keep our own reference
+        let reader = self.get_reader(&ruby)?.as_ref().clone().unwrap();
+        let metadata = reader.metadata();
+        let network = search_network(&ruby, network_arg, metadata.ip_version)?;
+        let description = match description {
+            Some(description) => writer::description_entries(&ruby, description)?,
+            None => metadata
+                .description
+                .iter()
+                .map(|(language, text)| (language.clone(), format!("{} (subset)", text)))
+                .collect(),
+        };
+        let aliases = reader
+            .aliases_ipv4()
+            .map_err(|e| iteration_error(&ruby, e))?;
+        let writer = Writer::from_metadata(metadata, description, aliases);
+        let fields = fields.as_deref();
+
+        let result = match filter {
+            None => {
+                let copy = || copy_networks(&reader, network, fields, &writer, |_, _| Ok(true));
+                if self.release_gvl {
+                    without_gvl(copy)
+                } else {
+                    copy()
+                }
+            }
+            Some(filter) => {
+                let ipaddr_class = ruby.class_object().const_get::<_, RClass>("IPAddr")?;
+                copy_networks(&reader, network, fields, &writer, |network, record| {
+                    let ipaddr =
+                        ipaddr_class.funcall::<_, _, Value>("new", (network.to_string(),))?;
+                    let keep =
+                        filter.funcall::<_, _, Value>("call", (ipaddr, record.to_ruby(&ruby)?))?;
+                    Ok(keep.to_bool())
+                })
             }
         };
+        result.map_err(|e| match e {
+            SubsetError::Database(e) => iteration_error(&ruby, e),
+            SubsetError::Ruby(e) => e,
+        })?;
 
-        // Open database with appropriate mode
-        match actual_mode {
-            "MMAP" => open_database_mmap(&database),
-            "MEMORY" => open_database_memory(&database),
-            _ => Err(Error::new(
+        writer.write(path)
+    }
+
+    /// Split `network` (default: the whole database) into `count` Cursors over
+    /// disjoint parts of the search tree, so they can be consumed from separate
+    /// threads. Each Cursor holds its own reference to the database. No more
+    /// Cursors are returned than `network` has possible parts with data.
+    fn each_partition(&self, args: &[Value]) -> Result<RArray, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(i64,), (Option<Value>,), (), (), (), ()>(args)?;
+        let (count,) = args.required;
+        let (network_arg,) = args.optional;
+        if count <= 0 {
+            return Err(Error::new(
                 ruby.exception_arg_error(),
-                format!("Invalid mode: {}", actual_mode),
-            )),
+                "Partition count must be positive",
+            ));
         }
+
+        let guard = self.get_reader(&ruby)?;
+        let reader_option = guard.as_ref();
+        let reader = reader_option.as_ref().unwrap();
+
+        let network = search_network(&ruby, network_arg, reader.metadata().ip_version)?;
+        let count = (count as u64).min(max_partitions(reader.metadata(), network)) as usize;
+        let partitions =
+            partition_networks(reader, network, count).map_err(|e| iteration_error(&ruby, e))?;
+
+        let cursors = ruby.ary_new_capa(count);
+        for pending in partitions {
+            cursors.push(Cursor {
+                state: Mutex::new(CursorState {
+                    iter: None,
+                    pending,
+                    position: None,
+                }),
+                source: reader.clone(),
+                decode: self.decode.clone(),
+            })?;
+        }
+        Ok(cursors)
     }
 
-    #[inline]
-    fn get(&self, ip_address: Value) -> Result<Value, Error> {
+    /// Create a Cursor over `network` (default: the whole database) that can be
+    /// advanced a step or a batch at a time. With `start_after:`, iteration resumes
+    /// after that network, as returned by `Cursor#position`, without walking the
+    /// part of the tree before it.
+    fn cursor(&self, args: &[Value]) -> Result<Cursor, Error> {
         let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
 
+        let args = scan_args::<(), (Option<Value>,), (), (), _, ()>(args)?;
+        let (network_arg,) = args.optional;
+        let kw = get_kwargs::<_, (), (Option<Value>,), ()>(args.keywords, &[], &["start_after"])?;
+        let (start_after,) = kw.optional;
+
         let guard = self.get_reader(&ruby)?;
         let reader_option = guard.as_ref();
         let reader = reader_option.as_ref().unwrap();
 
-        // Parse IP address
-        let parsed_ip = parse_ip_address_fast(ip_address, &ruby)?;
+        let ip_version = reader.metadata().ip_version;
+        let network = search_network(&ruby, network_arg, ip_version)?;
+        let (pending, position) = match start_after {
+            Some(start_after) => {
+                let start_after = search_network(&ruby, Some(start_after), ip_version)?;
+                (networks_after(network, start_after), Some(start_after))
+            }
+            None => (VecDeque::from([network]), None),
+        };
 
-        if self.ip_version == 4 && matches!(parsed_ip, IpAddr::V6(_)) {
-            return Err(Error::new(
-                ruby.exception_arg_error(),
-                ipv6_in_ipv4_error(&parsed_ip),
-            ));
+        Ok(Cursor {
+            state: Mutex::new(CursorState {
+                iter: None,
+                pending,
+                position,
+            }),
+            source: reader.clone(),
+            decode: self.decode.clone(),
+        })
+    }
+
+    /// Helper method to get the reader from the ArcSwapOption
+    fn get_reader(&self, ruby: &magnus::Ruby) -> Result<Guard<Option<Arc<ReaderSource>>>, Error> {
+        self.check_auto_reload(ruby)?;
+        let guard = self.reader.load();
+        if guard.is_none() {
+            return Err(Error::new(ruby.exception_runtime_error(), ERR_CLOSED_DB));
         }
+        Ok(guard)
+    }
 
-        // Perform lookup
-        match reader.lookup(parsed_ip) {
+    /// Parse `ip_address` and look it up in `reader`, returning the decoded record or nil
+    #[inline]
+    fn lookup_value(
+        &self,
+        ruby: &magnus::Ruby,
+        reader: &Arc<ReaderSource>,
+        ip_address: Value,
+        options: &DecodeOptions,
+    ) -> Result<Value, Error> {
+        let parsed_ip = self.parse_lookup_ip(ruby, ip_address)?;
+        let cache = self.cache_handle(ruby, reader, options);
+
+        match with_decode_options(options, || {
+            reader.lookup(parsed_ip, self.release_gvl, cache.as_ref())
+        }) {
             Ok(Some(data)) => Ok(data.into_value()),
             Ok(None) => Ok(ruby.qnil().as_value()),
-            Err(MaxMindDbError::InvalidDatabase { .. }) | Err(MaxMindDbError::Io(_)) => {
-                Err(Error::new(
-                    ExceptionClass::from_value(invalid_database_error().as_value())
-                        .expect("InvalidDatabaseError should convert to ExceptionClass"),
-                    ERR_BAD_DATA,
-                ))
-            }
-            Err(e) => Err(Error::new(
-                ruby.exception_runtime_error(),
-                format!("Database lookup failed: {}", e),
-            )),
+            Err(e) => Err(lookup_error(ruby, e)),
         }
     }
 
+    /// Parse `ip_address` and look it up in `reader`, returning its record as a JSON
+    /// String, or nil if it has none and `include_network` is false
+    fn lookup_json_value(
+        &self,
+        ruby: &magnus::Ruby,
+        reader: &ReaderSource,
+        ip_address: Value,
+        include_network: bool,
+    ) -> Result<Value, Error> {
+        let parsed_ip = self.parse_lookup_ip(ruby, ip_address)?;
+        let (record, network) = reader
+            .lookup_json(parsed_ip, self.release_gvl)
+            .map_err(|e| lookup_error(ruby, e))?;
+
+        let json = match (record, include_network) {
+            (Some(JsonRecord(json)), false) => json,
+            (None, false) => return Ok(ruby.qnil().as_value()),
+            (record, true) => {
+                let record = record.map_or_else(|| b"null".to_vec(), |JsonRecord(json)| json);
+                let network = network_for_ip(parsed_ip, network);
+                let mut json = Vec::with_capacity(record.len() + 64);
+                write!(json, "{{\"network\":\"{}\",\"data\":", network)
+                    .expect("writing to a Vec should not fail");
+                json.extend_from_slice(&record);
+                json.push(b'}');
+                json
+            }
+        };
+
+        // The decoder doesn't validate strings (see `unsafe-str-decode`), so check here
+        let json = String::from_utf8(json).map_err(|_| {
+            Error::new(
+                ExceptionClass::from_value(invalid_database_error().as_value())
+                    .expect("InvalidDatabaseError should convert to ExceptionClass"),
+                ERR_BAD_DATA,
+            )
+        })?;
+        Ok(ruby.str_new(&json).as_value())
+    }
+
+    /// Parse `ip_address` and decode only the value at `path` in its record
     #[inline]
-    fn get_with_prefix_length(&self, ip_address: Value) -> Result<RArray, Error> {
-        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+    fn lookup_path_value(
+        &self,
+        ruby: &magnus::Ruby,
+        reader: &ReaderSource,
+        ip_address: Value,
+        path: &[PathSegment],
+        options: &DecodeOptions,
+    ) -> Result<Value, Error> {
+        let parsed_ip = self.parse_lookup_ip(ruby, ip_address)?;
+        decode_path_value(ruby, reader, parsed_ip, path, options, self.release_gvl)
+    }
 
-        let guard = self.get_reader(&ruby)?;
+    /// Parse `ip_address` and look it up in `reader`, returning `[record, prefix_length]`
+    #[inline]
+    fn lookup_value_with_prefix(
+        &self,
+        ruby: &magnus::Ruby,
+        reader: &Arc<ReaderSource>,
+        ip_address: Value,
+        options: &DecodeOptions,
+    ) -> Result<RArray, Error> {
+        let parsed_ip = self.parse_lookup_ip(ruby, ip_address)?;
+        let cache = self.cache_handle(ruby, reader, options);
+
+        let (data, prefix) = with_decode_options(options, || {
+            reader.lookup_prefix(parsed_ip, self.release_gvl, cache.as_ref())
+        })
+        .map_err(|e| lookup_error(ruby, e))?;
+        let arr = ruby.ary_new_capa(2);
+        match data {
+            Some(data) => arr.push(data.into_value())?,
+            None => arr.push(ruby.qnil().as_value())?,
+        }
+        arr.push(prefix.into_value_with(ruby))?;
+        Ok(arr)
+    }
+
+    /// Look up an already parsed `ip` with the default options, returning its record,
+    /// if any, and the prefix length of its network in the address family of `ip`
+    fn lookup_record(&self, ruby: &magnus::Ruby, ip: IpAddr) -> Result<(Option<Value>, u8), Error> {
+        let guard = self.get_reader(ruby)?;
         let reader_option = guard.as_ref();
         let reader = reader_option.as_ref().unwrap();
 
-        // Parse IP address
-        let parsed_ip = parse_ip_address_fast(ip_address, &ruby)?;
+        let cache = self.cache_handle(ruby, reader, &self.decode);
+        let (data, network) = with_decode_options(&self.decode, || {
+            reader.lookup_network(ip, self.release_gvl, cache.as_ref())
+        })
+        .map_err(|e| lookup_error(ruby, e))?;
+        Ok((
+            data.map(|data| data.into_value()),
+            network_for_ip(ip, network).prefix(),
+        ))
+    }
+
+    /// The record cache for a lookup, if enabled and the lookup uses the default options
+    #[inline]
+    fn cache_handle<'a>(
+        &'a self,
+        ruby: &'a magnus::Ruby,
+        source: &'a Arc<ReaderSource>,
+        options: &DecodeOptions,
+    ) -> Option<CacheHandle<'a>> {
+        let cache = self.cache.as_deref()?;
+        if !ptr::eq(options, &self.decode) {
+            return None;
+        }
+        Some(CacheHandle {
+            ruby,
+            cache,
+            source,
+        })
+    }
+
+    /// Build the decode options for a single call from its keyword arguments.
+    ///
+    /// Returns None when no keywords were given so the reader's defaults are used.
+    fn call_decode_options(
+        &self,
+        ruby: &magnus::Ruby,
+        keywords: RHash,
+    ) -> Result<Option<DecodeOptions>, Error> {
+        if keywords.is_empty() {
+            return Ok(None);
+        }
+        let kw = get_kwargs::<
+            _,
+            (),
+            (
+                Option<RArray>,
+                Option<RArray>,
+                Option<bool>,
+                Option<Symbol>,
+                Option<bool>,
+            ),
+            (),
+        >(
+            keywords,
+            &[],
+            &["only", "locales", "collapse_names", "keys", "freeze"],
+        )?;
+        let (only, locales, collapse_names, keys, freeze) = kw.optional;
+
+        let mut options = self.decode.clone();
+        if let Some(only) = only {
+            options.fields = Some(parse_names(ruby, only, "Field names")?);
+        }
+        options.set_locales(ruby, locales, collapse_names)?;
+        options.set_output(ruby, keys, freeze)?;
+        Ok(Some(options))
+    }
+
+    /// Parse an IP address argument and make sure it can be looked up in this database
+    #[inline]
+    fn parse_lookup_ip(&self, ruby: &magnus::Ruby, ip_address: Value) -> Result<IpAddr, Error> {
+        let parsed_ip = parse_ip_address_fast(ip_address, ruby)?;
 
-        if self.ip_version == 4 && matches!(parsed_ip, IpAddr::V6(_)) {
+        if matches!(parsed_ip, IpAddr::V6(_)) && self.ip_version.load(Ordering::Acquire) == 4 {
             return Err(Error::new(
                 ruby.exception_arg_error(),
                 ipv6_in_ipv4_error(&parsed_ip),
             ));
         }
 
-        // Perform lookup with prefix
-        match reader.lookup_prefix(parsed_ip) {
-            Ok((Some(data), prefix)) => {
-                let arr = ruby.ary_new();
-                arr.push(data.into_value())?;
-                arr.push(prefix.into_value_with(&ruby))?;
-                Ok(arr)
-            }
-            Ok((None, prefix)) => {
-                let arr = ruby.ary_new();
-                arr.push(ruby.qnil().as_value())?;
-                arr.push(prefix.into_value_with(&ruby))?;
-                Ok(arr)
+        Ok(parsed_ip)
+    }
+}
+
+unsafe impl Send for Reader {}
+
+/// An owned segment of a record path, as passed to `Reader#dig`
+enum PathSegment {
+    Key(String),
+    Index(usize),
+    IndexFromEnd(usize),
+}
+
+impl PathSegment {
+    #[inline]
+    fn as_element(&self) -> PathElement<'_> {
+        match self {
+            PathSegment::Key(key) => PathElement::Key(key),
+            PathSegment::Index(index) => PathElement::Index(*index),
+            PathSegment::IndexFromEnd(index) => PathElement::IndexFromEnd(*index),
+        }
+    }
+}
+
+/// Convert a Ruby array of keys and indexes into path segments.
+///
+/// Strings and Symbols are map keys. Integers are array indexes, with negative values
+/// counting from the end as in `Array#[]` (-1 is the last element).
+fn parse_path(ruby: &magnus::Ruby, path: RArray) -> Result<Vec<PathSegment>, Error> {
+    let mut segments = Vec::with_capacity(path.len());
+    for i in 0..path.len() {
+        segments.push(parse_path_segment(ruby, path.entry(i as isize)?)?);
+    }
+    Ok(segments)
+}
+
+/// Convert a single path element: a String or Symbol key, or an Integer index
+fn parse_path_segment(ruby: &magnus::Ruby, element: Value) -> Result<PathSegment, Error> {
+    if let Some(key) = RString::from_value(element) {
+        Ok(PathSegment::Key(key.to_string()?))
+    } else if let Some(key) = Symbol::from_value(element) {
+        Ok(PathSegment::Key(key.name()?.into_owned()))
+    } else if let Some(index) = magnus::Integer::from_value(element) {
+        let index = index.to_i64()?;
+        if index >= 0 {
+            Ok(PathSegment::Index(index as usize))
+        } else {
+            Ok(PathSegment::IndexFromEnd((-(index + 1)) as usize))
+        }
+    } else {
+        Err(Error::new(
+            ruby.exception_arg_error(),
+            format!(
+                "Path elements must be Strings, Symbols, or Integers, got {}",
+                element.inspect()
+            ),
+        ))
+    }
+}
+
+/// Decode the value at `path` in the record for `ip`, or nil if the path doesn't exist
+fn decode_path_value(
+    ruby: &magnus::Ruby,
+    reader: &ReaderSource,
+    ip: IpAddr,
+    path: &[PathSegment],
+    options: &DecodeOptions,
+    release_gvl: bool,
+) -> Result<Value, Error> {
+    let options = options.for_path(path);
+    let path: Vec<PathElement<'_>> = path.iter().map(PathSegment::as_element).collect();
+
+    match with_decode_options(&options, || reader.lookup_path(ip, &path, release_gvl)) {
+        Ok(Some(data)) => Ok(data.into_value()),
+        Ok(None) => Ok(ruby.qnil().as_value()),
+        Err(e) => Err(lookup_error(ruby, e)),
+    }
+}
+
+/// Convert a Ruby array of Strings or Symbols, such as field names or locales
+fn parse_names(ruby: &magnus::Ruby, names: RArray, what: &str) -> Result<Arc<[String]>, Error> {
+    let mut parsed = Vec::with_capacity(names.len());
+    for i in 0..names.len() {
+        let name: Value = names.entry(i as isize)?;
+        let name = if let Some(name) = RString::from_value(name) {
+            name.to_string()?
+        } else if let Some(name) = Symbol::from_value(name) {
+            name.name()?.into_owned()
+        } else {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!(
+                    "{} must be Strings or Symbols, got {}",
+                    what,
+                    name.inspect()
+                ),
+            ));
+        };
+        parsed.push(name);
+    }
+    Ok(parsed.into())
+}
+
+/// How batch lookups handle a failure for an individual address
+#[derive(Clone, Copy)]
+enum BatchErrorPolicy {
+    /// Raise the error, aborting the whole batch
+    Raise,
+    /// Store nil in the failed slot
+    Nil,
+    /// Store the exception object in the failed slot
+    Exception,
+}
+
+impl BatchErrorPolicy {
+    fn from_symbol(ruby: &magnus::Ruby, symbol: Option<Symbol>) -> Result<Self, Error> {
+        let Some(symbol) = symbol else {
+            return Ok(BatchErrorPolicy::Raise);
+        };
+        match &*symbol.name()? {
+            "raise" => Ok(BatchErrorPolicy::Raise),
+            "nil" => Ok(BatchErrorPolicy::Nil),
+            "exception" => Ok(BatchErrorPolicy::Exception),
+            other => Err(Error::new(
+                ruby.exception_arg_error(),
+                format!(
+                    "Unsupported on_error value: {} (expected :raise, :nil, or :exception)",
+                    other
+                ),
+            )),
+        }
+    }
+
+    #[inline]
+    fn apply(self, ruby: &magnus::Ruby, result: Result<Value, Error>) -> Result<Value, Error> {
+        match (self, result) {
+            (_, Ok(value)) => Ok(value),
+            (BatchErrorPolicy::Raise, Err(e)) => Err(e),
+            (BatchErrorPolicy::Nil, Err(_)) => Ok(ruby.qnil().as_value()),
+            // Errors without an exception object (e.g. `throw`) are always propagated
+            (BatchErrorPolicy::Exception, Err(e)) => e.value().ok_or(e),
+        }
+    }
+}
+
+/// Parse the `(ips, on_error: :raise)` arguments shared by the batch lookup methods
+fn parse_batch_args(
+    ruby: &magnus::Ruby,
+    args: &[Value],
+) -> Result<(RArray, BatchErrorPolicy), Error> {
+    let args = scan_args::<(RArray,), (), (), (), _, ()>(args)?;
+    let (ips,) = args.required;
+    let kw = get_kwargs::<_, (), (Option<Symbol>,), ()>(args.keywords, &[], &["on_error"])?;
+    let (on_error,) = kw.optional;
+    Ok((ips, BatchErrorPolicy::from_symbol(ruby, on_error)?))
+}
+
+/// Convert an error from the maxminddb crate raised during a lookup into a Ruby exception
+fn lookup_error(ruby: &magnus::Ruby, e: MaxMindDbError) -> Error {
+    match e {
+        MaxMindDbError::InvalidDatabase { .. } | MaxMindDbError::Io(_) => Error::new(
+            ExceptionClass::from_value(invalid_database_error().as_value())
+                .expect("InvalidDatabaseError should convert to ExceptionClass"),
+            ERR_BAD_DATA,
+        ),
+        e => Error::new(
+            ruby.exception_runtime_error(),
+            format!("Database lookup failed: {}", e),
+        ),
+    }
+}
+
+/// Keyword options accepted by the Reader constructors
+struct ReaderOptions {
+    mode: Option<Symbol>,
+    release_gvl: Option<bool>,
+    fields: Option<RArray>,
+    locales: Option<RArray>,
+    collapse_names: Option<bool>,
+    keys: Option<Symbol>,
+    freeze: Option<bool>,
+    cache_size: Option<usize>,
+    auto_reload: Option<bool>,
+    check_interval: Option<f64>,
+    on_reload_error: Option<Value>,
+    member: Option<String>,
+}
+
+impl ReaderOptions {
+    fn parse(keywords: RHash) -> Result<Self, Error> {
+        let kw = get_kwargs::<
+            _,
+            (),
+            (
+                Option<Symbol>,
+                Option<bool>,
+                Option<RArray>,
+                Option<RArray>,
+                Option<bool>,
+                Option<Symbol>,
+                Option<bool>,
+                Option<usize>,
+                Option<bool>,
+                Option<f64>,
+                Option<Value>,
+                Option<String>,
+            ),
+            (),
+        >(
+            keywords,
+            &[],
+            &[
+                "mode",
+                "release_gvl",
+                "fields",
+                "locales",
+                "collapse_names",
+                "keys",
+                "freeze",
+                "cache_size",
+                "auto_reload",
+                "check_interval",
+                "on_reload_error",
+                "member",
+            ],
+        )?;
+        let (
+            mode,
+            release_gvl,
+            fields,
+            locales,
+            collapse_names,
+            keys,
+            freeze,
+            cache_size,
+            auto_reload,
+            check_interval,
+            on_reload_error,
+            member,
+        ) = kw.optional;
+        Ok(ReaderOptions {
+            mode,
+            release_gvl,
+            fields,
+            locales,
+            collapse_names,
+            keys,
+            freeze,
+            cache_size,
+            auto_reload,
+            check_interval,
+            on_reload_error,
+            member,
+        })
+    }
+
+    /// Build the auto-reload state for the database file at `path`, if enabled
+    fn auto_reload(&self, ruby: &magnus::Ruby, path: &str) -> Result<Option<AutoReload>, Error> {
+        match self.auto_reload {
+            Some(true) => Ok(Some(AutoReload::new(
+                ruby,
+                path,
+                self.check_interval,
+                self.on_reload_error,
+            )?)),
+            _ if self.check_interval.is_some() || self.on_reload_error.is_some() => {
+                Err(Error::new(
+                    ruby.exception_arg_error(),
+                    "check_interval and on_reload_error require auto_reload: true",
+                ))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Reject options that only make sense for a database opened from a file
+    fn ensure_no_file_options(&self, ruby: &magnus::Ruby) -> Result<(), Error> {
+        if self.mode.is_some()
+            || self.auto_reload.is_some()
+            || self.check_interval.is_some()
+            || self.on_reload_error.is_some()
+        {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "mode and auto_reload options require a database file",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Apply the lookup and decoding options to a newly created reader
+    fn configure(self, ruby: &magnus::Ruby, reader: &mut Reader) -> Result<(), Error> {
+        let cache_size = self.cache_size.filter(|&size| size > 0);
+        // Cached records are shared between callers, so they must be immutable
+        if cache_size.is_some() && self.freeze == Some(false) {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "cache_size can't be combined with freeze: false; cached records are always frozen",
+            ));
+        }
+        reader.release_gvl = self.release_gvl.unwrap_or(false);
+        if let Some(fields) = self.fields {
+            reader.decode.fields = Some(parse_names(ruby, fields, "Field names")?);
+        }
+        reader
+            .decode
+            .set_locales(ruby, self.locales, self.collapse_names)?;
+        reader.decode.set_output(ruby, self.keys, self.freeze)?;
+        if let Some(cache_size) = cache_size {
+            reader.decode.freeze = true;
+            reader.cache = Some(Arc::new(RecordCache::new(cache_size)));
+        }
+        Ok(())
+    }
+}
+
+/// Helper function to create a Reader from a ReaderSource loaded from `origin`
+fn create_reader(source: ReaderSource, origin: DatabaseOrigin) -> Reader {
+    let ip_version = source.metadata().ip_version;
+    let source = Arc::new(source);
+    Reader {
+        reader: Arc::new(ArcSwapOption::from(Some(source))),
+        closed: Arc::new(AtomicBool::new(false)),
+        ip_version: Arc::new(AtomicU16::new(ip_version)),
+        origin: Arc::new(Mutex::new(origin)),
+        auto_reload: None,
+        release_gvl: false,
+        decode: DecodeOptions::default(),
+        cache: None,
+    }
+}
+
+/// Express the network found for `ip` in the same address family as `ip`.
+///
+/// IPv6 databases store IPv4 addresses in the `::a.b.c.d` subtree, so an IPv4
+/// lookup there yields an IPv6 network. Networks wider than that subtree become
+/// `0.0.0.0/0`.
+fn network_for_ip(ip: IpAddr, network: IpNetwork) -> IpNetwork {
+    match (ip, network) {
+        (IpAddr::V4(_), IpNetwork::V6(network)) => {
+            let [.., a, b, c, d] = network.network().octets();
+            let prefix = network.prefix().saturating_sub(96);
+            let network = Ipv4Network::new(Ipv4Addr::new(a, b, c, d), prefix)
+                .expect("IPv4 prefix should be at most 32");
+            IpNetwork::V4(network)
+        }
+        _ => network,
+    }
+}
+
+/// Parse the network to iterate over from a String or IPAddr argument, defaulting to
+/// the whole database
+fn search_network(
+    ruby: &magnus::Ruby,
+    network_arg: Option<Value>,
+    ip_version: u16,
+) -> Result<IpNetwork, Error> {
+    let network = match network_arg {
+        Some(network_arg) => parse_network(ruby, network_arg)?,
+        None if ip_version == 4 => IpNetwork::V4(
+            Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).expect("prefix 0 should be valid"),
+        ),
+        None => IpNetwork::V6(
+            Ipv6Network::new(Ipv6Addr::UNSPECIFIED, 0).expect("prefix 0 should be valid"),
+        ),
+    };
+
+    // Validate network matches database IP version
+    // IPv4 in IPv6 DB is OK (IPv4-mapped), IPv6 in IPv6 DB is OK
+    if let (4, IpNetwork::V6(_)) = (ip_version, network) {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            format!(
+                "Cannot search for IPv6 network '{}' in an IPv4-only database",
+                network
+            ),
+        ));
+    }
+
+    Ok(network)
+}
+
+/// Parse a network CIDR from a String or IPAddr. A bare address is a single-host network.
+fn parse_network(ruby: &magnus::Ruby, network_arg: Value) -> Result<IpNetwork, Error> {
+    // Accept both String and IPAddr objects
+    let network_str = if let Ok(s) = RString::try_convert(network_arg) {
+        // It's already a string
+        s.to_string()?
+    } else {
+        // Check if it's an IPAddr object
+        let ipaddr_class = ruby.class_object().const_get::<_, RClass>("IPAddr")?;
+        if network_arg.is_kind_of(ipaddr_class) {
+            // It's an IPAddr - need to get both address and prefix
+            let ip_str: String = network_arg.funcall("to_s", ())?;
+
+            // Get the prefix length from IPAddr
+            // IPAddr stores prefix as a netmask, need to convert
+            let prefix_len: u8 = network_arg.funcall("prefix", ())?;
+
+            // Construct CIDR notation
+            format!("{}/{}", ip_str, prefix_len)
+        } else {
+            // Try to call to_s on it (works for other objects)
+            let to_s_result: Value = network_arg.funcall("to_s", ())?;
+            RString::try_convert(to_s_result)
+                .map_err(|_| {
+                    Error::new(
+                        ruby.exception_arg_error(),
+                        "Network parameter must be a String or IPAddr",
+                    )
+                })?
+                .to_string()?
+        }
+    };
+
+    IpNetwork::from_str(&network_str).map_err(|e| {
+        Error::new(
+            ruby.exception_arg_error(),
+            format!("Invalid network CIDR '{}': {}", network_str, e),
+        )
+    })
+}
+
+/// Convert an error from iterating over networks into a Ruby exception
+fn iteration_error(ruby: &magnus::Ruby, e: MaxMindDbError) -> Error {
+    match e {
+        MaxMindDbError::InvalidDatabase { .. } | MaxMindDbError::Io(_) => Error::new(
+            ExceptionClass::from_value(invalid_database_error().as_value())
+                .expect("InvalidDatabaseError should convert to ExceptionClass"),
+            ERR_BAD_DATA,
+        ),
+        e => Error::new(
+            ruby.exception_runtime_error(),
+            format!("Database iteration failed: {}", e),
+        ),
+    }
+}
+
+/// The first and last address of `network` as integers. IPv4 networks map to the
+/// `::a.b.c.d` range, where IPv6 databases store IPv4 addresses.
+fn network_range(network: IpNetwork) -> (u128, u128) {
+    let (start, host_bits) = match network {
+        IpNetwork::V4(network) => (u32::from(network.network()) as u128, 32 - network.prefix()),
+        IpNetwork::V6(network) => (u128::from(network.network()), 128 - network.prefix()),
+    };
+    (start, start | host_mask(host_bits))
+}
+
+#[inline]
+fn host_mask(host_bits: u8) -> u128 {
+    u128::MAX.checked_shr(128 - host_bits as u32).unwrap_or(0)
+}
+
+/// Split the part of `network` after `start_after` into the fewest CIDR blocks, in
+/// address order
+fn networks_after(network: IpNetwork, start_after: IpNetwork) -> VecDeque<IpNetwork> {
+    let (first, last) = network_range(network);
+    let (_, skip_to) = network_range(start_after);
+    let mut blocks = VecDeque::new();
+    if skip_to >= last {
+        return blocks;
+    }
+    let start = if skip_to < first { first } else { skip_to + 1 };
+    cidr_blocks(network, start, last)
+}
+
+/// Split the addresses from `start` to `last` into the fewest CIDR blocks, in address
+/// order, in the address family of `network`
+fn cidr_blocks(network: IpNetwork, mut start: u128, last: u128) -> VecDeque<IpNetwork> {
+    let mut blocks = VecDeque::new();
+    let max_host_bits = if network.is_ipv4() { 32 } else { 128 };
+    loop {
+        // The largest block aligned at `start` that doesn't extend past `last`
+        let mut host_bits = (start.trailing_zeros() as u8).min(max_host_bits);
+        while start | host_mask(host_bits) > last {
+            host_bits -= 1;
+        }
+        blocks.push_back(cidr_block(network, start, max_host_bits - host_bits));
+        let end = start | host_mask(host_bits);
+        if end == last {
+            return blocks;
+        }
+        start = end + 1;
+    }
+}
+
+/// The network at `start` with `prefix`, in the address family of `family`
+fn cidr_block(family: IpNetwork, start: u128, prefix: u8) -> IpNetwork {
+    match family {
+        IpNetwork::V4(_) => IpNetwork::V4(
+            Ipv4Network::new(Ipv4Addr::from(start as u32), prefix)
+                .expect("prefix should be at most 32"),
+        ),
+        IpNetwork::V6(_) => IpNetwork::V6(
+            Ipv6Network::new(Ipv6Addr::from(start), prefix).expect("prefix should be at most 128"),
+        ),
+    }
+}
+
+/// Most parts `network` can be split into: each part has a network with data, and
+/// there are at most as many of those as addresses in `network` or records in the
+/// search tree
+fn max_partitions(metadata: &maxminddb_crate::Metadata, network: IpNetwork) -> u64 {
+    let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+    let host_bits = u32::from(max_prefix - network.prefix());
+    let addresses = 1u64.checked_shl(host_bits).unwrap_or(u64::MAX);
+    addresses.min((u64::from(metadata.node_count) * 2).max(1))
+}
+
+/// Split `network` into `count` groups of disjoint blocks, in address order.
+///
+/// Blocks are halved level by level, following the search tree, and halves without
+/// any data are dropped, so the groups cover only populated subtrees. This keeps
+/// IPv6 databases, where most of the address space is empty, from putting all of
+/// their data into one group.
+fn partition_networks(
+    source: &ReaderSource,
+    network: IpNetwork,
+    count: usize,
+) -> Result<Vec<VecDeque<IpNetwork>>, MaxMindDbError> {
+    let has_data = |block: IpNetwork| -> Result<bool, MaxMindDbError> {
+        match source.within(block, WithinOptions::default())?.next() {
+            Some(result) => result.map(|_| true),
+            None => Ok(false),
+        }
+    };
+
+    let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+    let mut blocks = Vec::new();
+    if has_data(network)? {
+        blocks.push(network);
+    }
+    while blocks.len() < count && blocks.iter().any(|block| block.prefix() < max_prefix) {
+        let mut next = Vec::with_capacity(blocks.len() * 2);
+        for block in blocks {
+            if block.prefix() == max_prefix {
+                next.push(block);
+                continue;
             }
-            Err(MaxMindDbError::InvalidDatabase { .. }) | Err(MaxMindDbError::Io(_)) => {
-                Err(Error::new(
-                    ExceptionClass::from_value(invalid_database_error().as_value())
-                        .expect("InvalidDatabaseError should convert to ExceptionClass"),
-                    ERR_BAD_DATA,
-                ))
+            let (first, last) = network_range(block);
+            let half = first + (last - first) / 2 + 1;
+            for start in [first, half] {
+                let half = cidr_block(block, start, block.prefix() + 1);
+                if has_data(half)? {
+                    next.push(half);
+                }
             }
-            Err(e) => Err(Error::new(
-                ruby.exception_runtime_error(),
-                format!("Database lookup failed: {}", e),
-            )),
         }
+        blocks = next;
     }
 
-    fn metadata(&self) -> Result<Metadata, Error> {
-        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
-
-        let guard = self.get_reader(&ruby)?;
-        let reader_option = guard.as_ref();
-        let reader = reader_option.as_ref().unwrap();
-        let meta = reader.metadata();
-
-        Ok(Metadata {
-            binary_format_major_version: meta.binary_format_major_version,
-            binary_format_minor_version: meta.binary_format_minor_version,
-            build_epoch: meta.build_epoch,
-            database_type: meta.database_type.clone(),
-            description_map: meta.description.clone(),
-            ip_version: meta.ip_version,
-            languages_list: meta.languages.clone(),
-            node_count: meta.node_count,
-            record_size: meta.record_size,
+    Ok((0..count)
+        .map(|i| {
+            let bound = |i: usize| (i as u128 * blocks.len() as u128 / count as u128) as usize;
+            blocks[bound(i)..bound(i + 1)].iter().copied().collect()
         })
-    }
+        .collect())
+}
 
-    fn close(&self) {
-        if self.closed.swap(true, Ordering::AcqRel) {
-            return;
-        }
-        self.reader.store(None);
-    }
+/// A record serialized to JSON as it is decoded, without an intermediate value
+struct JsonRecord(Vec<u8>);
 
-    fn closed(&self) -> bool {
-        self.closed.load(Ordering::Acquire)
+impl<'de> Deserialize<'de> for JsonRecord {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut json = Vec::with_capacity(256);
+        serde_transcode::transcode(deserializer, &mut serde_json::Serializer::new(&mut json))
+            .map_err(de::Error::custom)?;
+        Ok(JsonRecord(json))
     }
+}
 
-    fn each(&self, args: &[Value]) -> Result<Value, Error> {
-        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+/// Export output is collected in a buffer of this size before each write
+const EXPORT_BUFFER_SIZE: usize = 64 * 1024;
 
-        let guard = self.get_reader(&ruby)?;
-        let reader_option = guard.as_ref();
-        let reader = reader_option.as_ref().unwrap();
+/// Output format for `Reader#export`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+    JsonLines,
+    Csv,
+}
 
-        // If no block given, return enumerator
-        if !ruby.block_given() {
-            return Err(Error::new(
-                ruby.exception_runtime_error(),
-                "Enumerator support not yet implemented, please provide a block",
-            ));
+impl ExportFormat {
+    fn from_symbol(ruby: &magnus::Ruby, symbol: Option<Symbol>) -> Result<Self, Error> {
+        let Some(symbol) = symbol else {
+            return Ok(ExportFormat::JsonLines);
+        };
+        match &*symbol.name()? {
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            "csv" => Ok(ExportFormat::Csv),
+            other => Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("Unsupported format: {} (expected :jsonl or :csv)", other),
+            )),
         }
+    }
+}
 
-        let ip_version = reader.metadata().ip_version;
+/// One segment of an export column path such as `subdivisions.0.iso_code`
+enum ColumnSegment {
+    Key(String),
+    Index(i64),
+}
 
-        // Determine the network to iterate over
-        let network_str = if args.is_empty() {
-            // No argument: use default (full database)
-            if ip_version == 4 {
-                "0.0.0.0/0".to_string()
-            } else {
-                "::/0".to_string()
-            }
-        } else {
-            // Argument provided: extract network CIDR string
-            let network_arg = args[0];
-
-            // Try to get string representation
-            // Accept both String and IPAddr objects
-            let network_str_val = if let Ok(s) = RString::try_convert(network_arg) {
-                // It's already a string
-                s.to_string()?
-            } else {
-                // Check if it's an IPAddr object
-                let ipaddr_class = ruby.class_object().const_get::<_, RClass>("IPAddr")?;
-                if network_arg.is_kind_of(ipaddr_class) {
-                    // It's an IPAddr - need to get both address and prefix
-                    let ip_str: String = network_arg.funcall("to_s", ())?;
+/// An export column: its name and the path to its value within each record
+struct ExportColumn {
+    name: String,
+    path: Vec<ColumnSegment>,
+}
 
-                    // Get the prefix length from IPAddr
-                    // IPAddr stores prefix as a netmask, need to convert
-                    let prefix_len: u8 = network_arg.funcall("prefix", ())?;
+impl ExportColumn {
+    fn parse(name: String) -> Self {
+        let path = name
+            .split('.')
+            .map(|segment| match segment.parse() {
+                Ok(index) => ColumnSegment::Index(index),
+                Err(_) => ColumnSegment::Key(segment.to_string()),
+            })
+            .collect();
+        ExportColumn { name, path }
+    }
 
-                    // Construct CIDR notation
-                    format!("{}/{}", ip_str, prefix_len)
-                } else {
-                    // Try to call to_s on it (works for other objects)
-                    let to_s_result: Value = network_arg.funcall("to_s", ())?;
-                    RString::try_convert(to_s_result)
-                        .map_err(|_| {
-                            Error::new(
-                                ruby.exception_arg_error(),
-                                "Network parameter must be a String or IPAddr",
-                            )
-                        })?
-                        .to_string()?
+    /// The value at this column's path, or None if the path doesn't exist
+    fn value<'a>(&self, record: &'a serde_json::Value) -> Option<&'a serde_json::Value> {
+        self.path
+            .iter()
+            .try_fold(record, |value, segment| match (segment, value) {
+                (ColumnSegment::Key(key), serde_json::Value::Object(map)) => map.get(key),
+                (ColumnSegment::Index(index), serde_json::Value::Array(array)) => {
+                    let index = if *index < 0 {
+                        array.len().checked_sub(index.unsigned_abs() as usize)?
+                    } else {
+                        *index as usize
+                    };
+                    array.get(index)
                 }
-            };
+                // Numeric keys, such as those in some custom databases
+                (ColumnSegment::Index(index), serde_json::Value::Object(map)) => {
+                    map.get(&index.to_string())
+                }
+                _ => None,
+            })
+    }
+}
 
-            network_str_val
-        };
+/// Error from `Exporter::run`, converted to a Ruby exception once the GVL is held
+enum ExportError {
+    Database(MaxMindDbError),
+    /// A record has a string that isn't valid UTF-8
+    InvalidUtf8,
+    Io(io::Error),
+}
 
-        let network = IpNetwork::from_str(&network_str).map_err(|e| {
-            Error::new(
-                ruby.exception_arg_error(),
-                format!("Invalid network CIDR '{}': {}", network_str, e),
-            )
-        })?;
+impl From<MaxMindDbError> for ExportError {
+    fn from(e: MaxMindDbError) -> Self {
+        ExportError::Database(e)
+    }
+}
 
-        // Validate network matches database IP version
-        // IPv4 in IPv6 DB is OK (IPv4-mapped), IPv6 in IPv6 DB is OK
-        if let (4, IpNetwork::V6(_)) = (ip_version, network) {
-            return Err(Error::new(
-                ruby.exception_arg_error(),
-                format!(
-                    "Cannot search for IPv6 network '{}' in an IPv4-only database",
-                    network_str
-                ),
-            ));
-        }
+impl From<io::Error> for ExportError {
+    fn from(e: io::Error) -> Self {
+        ExportError::Io(e)
+    }
+}
 
-        let mut iter = reader.within(network).map_err(|e| {
-            Error::new(
-                ExceptionClass::from_value(invalid_database_error().as_value())
-                    .expect("InvalidDatabaseError should convert to ExceptionClass"),
-                format!("Failed to iterate: {}", e),
-            )
-        })?;
+impl From<serde_json::Error> for ExportError {
+    fn from(e: serde_json::Error) -> Self {
+        ExportError::Io(e.into())
+    }
+}
 
-        // Get IPAddr class
-        let ipaddr_class = ruby.class_object().const_get::<_, RClass>("IPAddr")?;
+/// Serializes networks and their records as JSON Lines or CSV without creating Ruby
+/// objects, so it can run while the GVL is released
+struct Exporter {
+    format: ExportFormat,
+    columns: Vec<ExportColumn>,
+}
 
-        // Iterate over all networks
-        while let Some(result) = iter.next() {
-            match result {
-                Ok((network, data)) => {
-                    // Convert IpNetwork to IPAddr
-                    let ip_str = network.to_string();
-                    let ipaddr = ipaddr_class.funcall::<_, _, Value>("new", (ip_str,))?;
+impl Exporter {
+    /// Write the header, if any, then every network with data within `network`.
+    /// Returns the number of networks written.
+    fn run<W: Write>(
+        &self,
+        source: &ReaderSource,
+        network: IpNetwork,
+        out: &mut W,
+    ) -> Result<usize, ExportError> {
+        if self.format == ExportFormat::Csv {
+            out.write_all(b"network")?;
+            for column in &self.columns {
+                out.write_all(b",")?;
+                write_csv_field(out, &column.name)?;
+            }
+            out.write_all(b"\n")?;
+        }
 
-                    // Yield [network, data] to block
-                    let values = (ipaddr, data.into_value());
-                    ruby.yield_values::<(Value, Value), Value>(values)?;
+        let mut iter = source.within(network, WithinOptions::default())?;
+        let mut count = 0;
+        if self.columns.is_empty() {
+            // Whole records are transcoded straight from the decoder
+            while let Some(result) = iter.next_as::<JsonRecord>() {
+                let (network, Some(JsonRecord(record))) = result? else {
+                    continue;
+                };
+                // The decoder doesn't validate strings (see `unsafe-str-decode`)
+                if std::str::from_utf8(&record).is_err() {
+                    return Err(ExportError::InvalidUtf8);
                 }
-                Err(MaxMindDbError::InvalidDatabase { .. }) | Err(MaxMindDbError::Io(_)) => {
-                    return Err(Error::new(
-                        ExceptionClass::from_value(invalid_database_error().as_value())
-                            .expect("InvalidDatabaseError should convert to ExceptionClass"),
-                        ERR_BAD_DATA,
-                    ));
+                write!(out, "{{\"network\":\"{}\",\"data\":", network)?;
+                out.write_all(&record)?;
+                out.write_all(b"}\n")?;
+                count += 1;
+            }
+        } else {
+            // Each row is checked as a whole before it is written
+            let mut row = Vec::new();
+            while let Some(result) = iter.next_as::<serde_json::Value>() {
+                let (network, Some(record)) = result? else {
+                    continue;
+                };
+                row.clear();
+                self.write_columns(&mut row, network, &record)?;
+                if std::str::from_utf8(&row).is_err() {
+                    return Err(ExportError::InvalidUtf8);
                 }
-                Err(e) => {
-                    return Err(Error::new(
-                        ruby.exception_runtime_error(),
-                        format!("Database iteration failed: {}", e),
-                    ));
+                out.write_all(&row)?;
+                count += 1;
+            }
+        }
+        out.flush()?;
+        Ok(count)
+    }
+
+    fn write_columns<W: Write>(
+        &self,
+        out: &mut W,
+        network: IpNetwork,
+        record: &serde_json::Value,
+    ) -> Result<(), ExportError> {
+        match self.format {
+            ExportFormat::JsonLines => {
+                write!(out, "{{\"network\":\"{}\"", network)?;
+                for column in &self.columns {
+                    out.write_all(b",")?;
+                    serde_json::to_writer(&mut *out, &column.name)?;
+                    out.write_all(b":")?;
+                    let value = column.value(record).unwrap_or(&serde_json::Value::Null);
+                    serde_json::to_writer(&mut *out, value)?;
+                }
+                out.write_all(b"}\n")?;
+            }
+            ExportFormat::Csv => {
+                write!(out, "{}", network)?;
+                for column in &self.columns {
+                    out.write_all(b",")?;
+                    match column.value(record) {
+                        None | Some(serde_json::Value::Null) => {}
+                        Some(serde_json::Value::String(s)) => write_csv_field(out, s)?,
+                        Some(
+                            value @ (serde_json::Value::Bool(_) | serde_json::Value::Number(_)),
+                        ) => write!(out, "{}", value)?,
+                        // Maps and arrays are written as JSON
+                        Some(value) => write_csv_field(out, &value.to_string())?,
+                    }
                 }
+                out.write_all(b"\n")?;
             }
         }
+        Ok(())
+    }
+}
 
-        Ok(ruby.qnil().as_value())
+/// Error from `copy_networks`. Ruby exceptions only come from the `where:` block,
+/// which runs with the GVL held.
+enum SubsetError {
+    Database(MaxMindDbError),
+    Ruby(Error),
+}
+
+impl From<MaxMindDbError> for SubsetError {
+    fn from(e: MaxMindDbError) -> Self {
+        SubsetError::Database(e)
     }
+}
 
-    /// Helper method to get the reader from the ArcSwapOption
-    fn get_reader(&self, ruby: &magnus::Ruby) -> Result<Guard<Option<Arc<ReaderSource>>>, Error> {
-        let guard = self.reader.load();
-        if guard.is_none() {
-            return Err(Error::new(ruby.exception_runtime_error(), ERR_CLOSED_DB));
+impl From<Error> for SubsetError {
+    fn from(e: Error) -> Self {
+        SubsetError::Ruby(e)
+    }
+}
+
+/// Insert every network with data within `network` into `writer` if `keep` accepts
+/// its full record, keeping only `fields` of the record when given. Records are
+/// decoded with their stored types, so values are written back unchanged.
+fn copy_networks(
+    source: &ReaderSource,
+    network: IpNetwork,
+    fields: Option<&[String]>,
+    writer: &Writer,
+    mut keep: impl FnMut(IpNetwork, &WriterValue) -> Result<bool, SubsetError>,
+) -> Result<(), SubsetError> {
+    let mut iter = source.within(network, WithinOptions::default())?;
+    while let Some(result) = iter.next_as::<WriterValue>() {
+        let (network, Some(record)) = result? else {
+            continue;
+        };
+        if !keep(network, &record)? {
+            continue;
         }
-        Ok(guard)
+        let record = match fields {
+            Some(fields) => record.project(fields),
+            None => record,
+        };
+        // The Writer has the source's IP version and only aliases ranges the source
+        // aliases, which have no networks of their own, so every network fits
+        let stored = writer.insert_value(network, record);
+        debug_assert!(stored, "{} should fit the subset database", network);
     }
+    Ok(())
 }
 
-unsafe impl Send for Reader {}
+/// Write `field`, quoting it if it contains a comma, quote, or line break
+fn write_csv_field<W: Write>(out: &mut W, field: &str) -> io::Result<()> {
+    if field.contains([',', '"', '\n', '\r']) {
+        write!(out, "\"{}\"", field.replace('"', "\"\""))
+    } else {
+        out.write_all(field.as_bytes())
+    }
+}
 
-/// Helper function to create a Reader from a ReaderSource
-fn create_reader(source: ReaderSource) -> Reader {
-    let ip_version = source.metadata().ip_version;
-    let source = Arc::new(source);
-    Reader {
-        reader: Arc::new(ArcSwapOption::from(Some(source))),
-        closed: Arc::new(AtomicBool::new(false)),
-        ip_version,
+/// Adapts a Ruby IO to `Write`. A Ruby exception raised by `write` is kept so it can
+/// be re-raised unchanged.
+struct RubyIoWriter {
+    io: Value,
+    error: Option<Error>,
+}
+
+impl Write for RubyIoWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        match self
+            .io
+            .funcall::<_, _, Value>("write", (ruby.str_from_slice(buf),))
+        {
+            Ok(_) => Ok(buf.len()),
+            Err(e) => {
+                self.error = Some(e);
+                Err(io::Error::other("IO#write raised an exception"))
+            }
+        }
     }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Build an IPAddr for the network found for `ip`, in the same address family
+fn network_to_ipaddr(ruby: &magnus::Ruby, ip: IpAddr, network: IpNetwork) -> Result<Value, Error> {
+    let ipaddr_class = ruby.class_object().const_get::<_, RClass>("IPAddr")?;
+    let network = network_for_ip(ip, network).to_string();
+    ipaddr_class.funcall("new", (network,))
 }
 
 /// Parse IP address from Ruby value (String or IPAddr) - optimized version
@@ -961,8 +3521,165 @@ fn ipv6_in_ipv4_error(ip: &IpAddr) -> String {
     )
 }
 
+/// How a database file is loaded
+#[derive(Clone, Copy)]
+enum OpenMode {
+    /// Memory-map the file (MODE_MMAP and MODE_AUTO)
+    Mmap,
+    /// Read the whole file into memory (MODE_MEMORY)
+    Memory,
+}
+
+impl OpenMode {
+    fn from_symbol(ruby: &magnus::Ruby, mode: Symbol) -> Result<Self, Error> {
+        match &*mode.name()? {
+            "MODE_AUTO" | "MODE_MMAP" => Ok(OpenMode::Mmap),
+            "MODE_MEMORY" => Ok(OpenMode::Memory),
+            other => Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("Unsupported mode: {}", other),
+            )),
+        }
+    }
+}
+
+/// Where a reader's database was loaded from, used as the default for `reload!`
+struct DatabaseOrigin {
+    /// None for databases created from bytes or an IO
+    path: Option<String>,
+    mode: OpenMode,
+    /// Name of the database inside a tar archive
+    member: Option<String>,
+}
+
+impl DatabaseOrigin {
+    fn in_memory() -> Self {
+        DatabaseOrigin {
+            path: None,
+            mode: OpenMode::Memory,
+            member: None,
+        }
+    }
+}
+
+/// Identity of a database file on disk, used to notice when it has been replaced
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct FileFingerprint {
+    inode: u64,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileFingerprint {
+    fn of(path: &str) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&meta);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Some(FileFingerprint {
+            inode,
+            modified: meta.modified().ok(),
+            len: meta.len(),
+        })
+    }
+}
+
+/// Default seconds between file checks for `auto_reload: true`
+const DEFAULT_CHECK_INTERVAL: f64 = 60.0;
+
+/// Settings and state for `auto_reload: true`.
+///
+/// Like the record cache, the state lock is never held across a Ruby allocation
+/// or call, so the GC mark function can take it.
+struct AutoReload {
+    interval: Duration,
+    on_error: Option<Opaque<Value>>,
+    state: Mutex<AutoReloadState>,
+}
+
+struct AutoReloadState {
+    next_check: Instant,
+    /// Fingerprint of the file most recently loaded or attempted
+    fingerprint: Option<FileFingerprint>,
+    /// Exception from the most recent failed reload
+    last_error: Option<Opaque<Value>>,
+}
+
+impl AutoReload {
+    fn new(
+        ruby: &magnus::Ruby,
+        path: &str,
+        check_interval: Option<f64>,
+        on_error: Option<Value>,
+    ) -> Result<Self, Error> {
+        let interval = check_interval.unwrap_or(DEFAULT_CHECK_INTERVAL);
+        if !interval.is_finite() || interval < 0.0 {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!(
+                    "check_interval must be a non-negative number, got {}",
+                    interval
+                ),
+            ));
+        }
+        if let Some(callback) = on_error {
+            if !callback.respond_to("call", false)? {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    "on_reload_error must respond to call",
+                ));
+            }
+        }
+        let interval = Duration::from_secs_f64(interval);
+        Ok(AutoReload {
+            interval,
+            on_error: on_error.map(Opaque::from),
+            state: Mutex::new(AutoReloadState {
+                next_check: Instant::now() + interval,
+                fingerprint: FileFingerprint::of(path),
+                last_error: None,
+            }),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, AutoReloadState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// If a check is due, schedule the next one and return the fingerprint to
+    /// compare against. Returns None while another thread is checking.
+    fn start_check(&self) -> Option<Option<FileFingerprint>> {
+        let mut state = self.state.try_lock().ok()?;
+        let now = Instant::now();
+        if now < state.next_check {
+            return None;
+        }
+        state.next_check = now + self.interval;
+        Some(state.fingerprint)
+    }
+
+    fn mark(&self, ruby: &magnus::Ruby, marker: &Marker) {
+        if let Some(callback) = self.on_error {
+            marker.mark(ruby.get_inner(callback));
+        }
+        if let Some(error) = self.lock().last_error {
+            marker.mark(ruby.get_inner(error));
+        }
+    }
+}
+
+/// Open and validate a MaxMind DB file using `mode`, extracting it first if the
+/// file is compressed or a tar archive
+fn open_database(path: &str, mode: OpenMode, member: Option<&str>) -> Result<ReaderSource, Error> {
+    match mode {
+        OpenMode::Mmap => open_database_mmap(path, member),
+        OpenMode::Memory => open_database_memory(path, member),
+    }
+}
+
 /// Open a MaxMind DB using memory-mapped I/O (MODE_MMAP)
-fn open_database_mmap(path: &str) -> Result<Reader, Error> {
+fn open_database_mmap(path: &str, member: Option<&str>) -> Result<ReaderSource, Error> {
     let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby context");
 
     let file = File::open(Path::new(path)).map_err(|e| match e.kind() {
@@ -990,6 +3707,12 @@ fn open_database_mmap(path: &str) -> Result<Reader, Error> {
         )
     })?;
 
+    // Archives can't be used in place, so their contents are always held in memory
+    let what = format!("database file ({})", path);
+    if let Some(database) = unpack_archive(&mmap, member, &what)? {
+        return open_memory_source(MemorySource::Owned(database), None, &what);
+    }
+
     let reader = MaxMindReader::from_source(mmap).map_err(|_| {
         Error::new(
             ExceptionClass::from_value(invalid_database_error().as_value())
@@ -1001,11 +3724,11 @@ fn open_database_mmap(path: &str) -> Result<Reader, Error> {
         )
     })?;
 
-    Ok(create_reader(ReaderSource::Mmap(reader)))
+    Ok(ReaderSource::Mmap(reader))
 }
 
 /// Open a MaxMind DB by loading entire file into memory (MODE_MEMORY)
-fn open_database_memory(path: &str) -> Result<Reader, Error> {
+fn open_database_memory(path: &str, member: Option<&str>) -> Result<ReaderSource, Error> {
     let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby context");
 
     let mut file = File::open(Path::new(path)).map_err(|e| match e.kind() {
@@ -1034,18 +3757,145 @@ fn open_database_memory(path: &str) -> Result<Reader, Error> {
         )
     })?;
 
+    open_memory_source(
+        MemorySource::Owned(buffer),
+        member,
+        &format!("database file ({})", path),
+    )
+}
+
+/// Validate an in-memory database, describing it as `what` in errors
+fn open_memory_source(
+    buffer: MemorySource,
+    member: Option<&str>,
+    what: &str,
+) -> Result<ReaderSource, Error> {
+    let buffer = match unpack_archive(buffer.as_ref(), member, what)? {
+        Some(database) => MemorySource::Owned(database),
+        None => buffer,
+    };
     let reader = MaxMindReader::from_source(buffer).map_err(|_| {
         Error::new(
             ExceptionClass::from_value(invalid_database_error().as_value())
                 .expect("InvalidDatabaseError should convert to ExceptionClass"),
-            format!(
-                "Error opening database file ({}). Is this a valid MaxMind DB file?",
-                path
-            ),
+            format!("Error opening {}. Is this a valid MaxMind DB file?", what),
         )
     })?;
 
-    Ok(create_reader(ReaderSource::Memory(reader)))
+    Ok(ReaderSource::Memory(reader))
+}
+
+/// Magic bytes at the start of a gzip stream
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Location of the magic field in a POSIX tar header
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// Largest database decompressed from gzip. The format's 32-bit data section
+/// pointers can't address more, and the limit keeps a gzip bomb from using
+/// unbounded memory.
+const MAX_DECOMPRESSED_SIZE: u64 = 4 << 30;
+
+/// Extract the database from `bytes` if they are gzip-compressed, a tar archive,
+/// or both. Returns None if `bytes` are neither, so they can be used as is.
+fn unpack_archive(
+    bytes: &[u8],
+    member: Option<&str>,
+    what: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    if !bytes.starts_with(GZIP_MAGIC) {
+        return extract_tar_member(bytes, member, what);
+    }
+
+    let mut decompressed = Vec::new();
+    MultiGzDecoder::new(bytes)
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| archive_error(what, format!("gzip data is corrupt: {}", e)))?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(archive_error(
+            what,
+            format!(
+                "gzip data decompresses to more than {} bytes",
+                MAX_DECOMPRESSED_SIZE
+            ),
+        ));
+    }
+    match extract_tar_member(&decompressed, member, what)? {
+        Some(database) => Ok(Some(database)),
+        None => Ok(Some(decompressed)),
+    }
+}
+
+/// Read the database out of tar archive `bytes`: the entry named `member`, matched
+/// against its full path or file name, or else the first `.mmdb` entry. Returns
+/// None if `bytes` are not a tar archive.
+fn extract_tar_member(
+    bytes: &[u8],
+    member: Option<&str>,
+    what: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    let is_tar = bytes.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC);
+    if !is_tar {
+        return match member {
+            Some(_) => {
+                let ruby =
+                    magnus::Ruby::get().expect("Ruby VM should be available in Ruby context");
+                Err(Error::new(
+                    ruby.exception_arg_error(),
+                    format!("member: given but {} is not a tar archive", what),
+                ))
+            }
+            None => Ok(None),
+        };
+    }
+
+    let tar_error =
+        |e: std::io::Error| archive_error(what, format!("tar archive is corrupt: {}", e));
+    let mut archive = TarArchive::new(bytes);
+    for entry in archive.entries().map_err(tar_error)? {
+        let mut entry = entry.map_err(tar_error)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path().map_err(tar_error)?;
+        let found = match member {
+            Some(name) => path == Path::new(name) || path.file_name() == Some(OsStr::new(name)),
+            None => path.extension() == Some(OsStr::new("mmdb")),
+        };
+        if found {
+            let path = path.into_owned();
+            // The header's size can't be trusted, so preallocate no more than the archive
+            let size = entry.size();
+            let mut database = Vec::with_capacity(size.min(bytes.len() as u64) as usize);
+            entry.read_to_end(&mut database).map_err(tar_error)?;
+            if database.len() as u64 != size {
+                return Err(archive_error(
+                    what,
+                    format!("tar member {} is truncated", path.display()),
+                ));
+            }
+            return Ok(Some(database));
+        }
+    }
+
+    Err(archive_error(
+        what,
+        match member {
+            Some(name) => format!("tar archive has no member named {}", name),
+            None => "tar archive has no .mmdb member".to_string(),
+        },
+    ))
+}
+
+/// Build an InvalidDatabaseError for an archive that couldn't be unpacked
+fn archive_error(what: &str, reason: String) -> Error {
+    Error::new(
+        ExceptionClass::from_value(invalid_database_error().as_value())
+            .expect("InvalidDatabaseError should convert to ExceptionClass"),
+        format!("Error opening {}: {}", what, reason),
+    )
 }
 
 /// Get the InvalidDatabaseError class
@@ -1103,20 +3953,83 @@ fn init(ruby: &magnus::Ruby) -> Result<(), Error> {
     // Define Reader class
     let reader_class = rust.define_class("Reader", ruby.class_object())?;
     reader_class.define_singleton_method("new", magnus::function!(Reader::new, -1))?;
-    reader_class.define_method("get", magnus::method!(Reader::get, 1))?;
+    reader_class
+        .define_singleton_method("from_bytes", magnus::function!(Reader::from_bytes, -1))?;
+    reader_class.define_singleton_method("from_io", magnus::function!(Reader::from_io, -1))?;
+    reader_class.define_method("get", magnus::method!(Reader::get, -1))?;
+    reader_class.define_method("dig", magnus::method!(Reader::dig, -1))?;
     reader_class.define_method(
         "get_with_prefix_length",
         magnus::method!(Reader::get_with_prefix_length, 1),
     )?;
+    reader_class.define_method(
+        "get_with_network",
+        magnus::method!(Reader::get_with_network, 1),
+    )?;
+    reader_class.define_method("lookup", magnus::method!(Reader::lookup, 1))?;
+    reader_class.define_method("get_json", magnus::method!(Reader::get_json, -1))?;
+    reader_class.define_method("get_lazy", magnus::method!(Reader::get_lazy, 1))?;
+    reader_class.define_method("get_many", magnus::method!(Reader::get_many, -1))?;
+    reader_class.define_method(
+        "get_many_with_prefix_length",
+        magnus::method!(Reader::get_many_with_prefix_length, -1),
+    )?;
+    reader_class.define_method("get_many_json", magnus::method!(Reader::get_many_json, -1))?;
     reader_class.define_method("metadata", magnus::method!(Reader::metadata, 0))?;
     reader_class.define_method("close", magnus::method!(Reader::close, 0))?;
     reader_class.define_method("closed", magnus::method!(Reader::closed, 0))?;
+    reader_class.define_method("reload!", magnus::method!(Reader::reload, -1))?;
+    reader_class.define_method(
+        "last_reload_error",
+        magnus::method!(Reader::last_reload_error, 0),
+    )?;
+    reader_class.define_method("cache_stats", magnus::method!(Reader::cache_stats, 0))?;
     reader_class.define_method("each", magnus::method!(Reader::each, -1))?;
+    reader_class.define_method("cursor", magnus::method!(Reader::cursor, -1))?;
+    reader_class.define_method("export", magnus::method!(Reader::export, -1))?;
+    reader_class.define_method("write_subset", magnus::method!(Reader::write_subset, -1))?;
+    reader_class.define_method(
+        "each_partition",
+        magnus::method!(Reader::each_partition, -1),
+    )?;
 
     // Include Enumerable module
     let enumerable = ruby.class_object().const_get::<_, RModule>("Enumerable")?;
     reader_class.include_module(enumerable)?;
 
+    // Define Cursor class
+    let cursor_class = rust.define_class("Cursor", ruby.class_object())?;
+    cursor_class.define_method("next", magnus::method!(Cursor::next, 0))?;
+    cursor_class.define_method("next_batch", magnus::method!(Cursor::next_batch, 1))?;
+    cursor_class.define_method("position", magnus::method!(Cursor::position, 0))?;
+    cursor_class.define_method("each", magnus::method!(Cursor::each, 0))?;
+    cursor_class.include_module(enumerable)?;
+
+    // Define Record class
+    let record_class = rust.define_class("Record", ruby.class_object())?;
+    record_class.define_method("[]", magnus::method!(LazyRecord::aref, 1))?;
+    record_class.define_method("dig", magnus::method!(LazyRecord::dig, -1))?;
+    record_class.define_method("fetch", magnus::method!(LazyRecord::fetch, -1))?;
+    record_class.define_method("to_h", magnus::method!(LazyRecord::to_h, 0))?;
+    record_class.define_method("each", magnus::method!(LazyRecord::each, 0))?;
+    record_class.define_method("data_offset", magnus::method!(LazyRecord::data_offset, 0))?;
+    record_class.include_module(enumerable)?;
+
+    // Define LookupResult class
+    let lookup_result_class = rust.define_class("LookupResult", ruby.class_object())?;
+    lookup_result_class.define_method("found?", magnus::method!(ReaderLookupResult::found, 0))?;
+    lookup_result_class
+        .define_method("network", magnus::method!(ReaderLookupResult::network, 0))?;
+    lookup_result_class.define_method(
+        "prefix_length",
+        magnus::method!(ReaderLookupResult::prefix_length, 0),
+    )?;
+    lookup_result_class.define_method(
+        "data_offset",
+        magnus::method!(ReaderLookupResult::data_offset, 0),
+    )?;
+    lookup_result_class.define_method("data", magnus::method!(ReaderLookupResult::data, 0))?;
+
     // Define Metadata class
     let metadata_class = rust.define_class("Metadata", ruby.class_object())?;
     metadata_class.define_method(
@@ -1143,6 +4056,12 @@ fn init(ruby: &magnus::Ruby) -> Result<(), Error> {
         magnus::method!(Metadata::search_tree_size, 0),
     )?;
 
+    // Define Writer class
+    writer::init(ruby, rust)?;
+
+    // Define OverlayReader class
+    overlay::init(ruby, rust)?;
+
     // Define MODE constants
     rust.const_set("MODE_AUTO", ruby.to_symbol("MODE_AUTO"))?;
     rust.const_set("MODE_MEMORY", ruby.to_symbol("MODE_MEMORY"))?;
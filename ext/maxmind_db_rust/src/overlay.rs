@@ -0,0 +1,413 @@
+//! Layering override records on a database: `MaxMind::DB::Rust::OverlayReader`
+
+use crate::{parse_network, Metadata, Reader};
+use ipnetwork::IpNetwork;
+use magnus::{
+    error::Error, gc::Marker, prelude::*, r_hash::ForEach, scan_args::get_kwargs,
+    scan_args::scan_args, value::BoxValue, DataTypeFunctions, IntoValue, RArray, RHash, RModule,
+    RString, Symbol, TryConvert, Value,
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::Ordering,
+};
+
+/// How `OverlayReader` combines an override with the base record for the same address
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OverlayMerge {
+    /// The override is returned as is
+    Replace,
+    /// Top-level keys of the override are added to the base record
+    Shallow,
+    /// Hashes are merged recursively
+    Deep,
+}
+
+impl OverlayMerge {
+    fn from_symbol(ruby: &magnus::Ruby, symbol: Option<Symbol>) -> Result<Self, Error> {
+        let Some(symbol) = symbol else {
+            return Ok(OverlayMerge::Replace);
+        };
+        match &*symbol.name()? {
+            "replace" => Ok(OverlayMerge::Replace),
+            "shallow" => Ok(OverlayMerge::Shallow),
+            "deep" => Ok(OverlayMerge::Deep),
+            other => Err(Error::new(
+                ruby.exception_arg_error(),
+                format!(
+                    "Unsupported merge value: {} (expected :replace, :shallow, or :deep)",
+                    other
+                ),
+            )),
+        }
+    }
+}
+
+/// A binary trie of override networks for one address family
+#[derive(Default)]
+struct PrefixTree {
+    /// The root is node 0, created with the first insert
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: [Option<usize>; 2],
+    /// Index of the record in `Overrides::Tree::records`
+    record: Option<usize>,
+}
+
+impl PrefixTree {
+    /// Store `record` for the network with `prefix` leading bits of `address`, in a
+    /// tree of `depth` bits
+    fn insert(&mut self, address: u128, prefix: u8, depth: u8, record: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push(TrieNode::default());
+        }
+        let mut node = 0;
+        for bit in 0..prefix {
+            let side = ((address >> (depth - 1 - bit)) & 1) as usize;
+            node = match self.nodes[node].children[side] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[side] = Some(child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].record = Some(record);
+    }
+
+    /// Find the longest matching network for `address`, returning its record and
+    /// prefix length. The other prefix length returned is that of the largest network
+    /// around `address` without any other override in it, so the result holds for
+    /// every address in it.
+    fn lookup(&self, address: u128, depth: u8) -> (Option<(usize, u8)>, u8) {
+        let Some(root) = self.nodes.first() else {
+            return (None, 0);
+        };
+        let mut node = root;
+        let mut best = node.record.map(|record| (record, 0));
+        for bit in 0..depth {
+            let side = ((address >> (depth - 1 - bit)) & 1) as usize;
+            match node.children[side] {
+                Some(child) => node = &self.nodes[child],
+                None if node.children == [None, None] => return (best, bit),
+                None => return (best, bit + 1),
+            }
+            if let Some(record) = node.record {
+                best = Some((record, bit + 1));
+            }
+        }
+        (best, depth)
+    }
+}
+
+/// Where override records come from
+enum Overrides {
+    /// Networks from a Hash, with IPv4 networks kept apart from IPv6 ones so IPv4
+    /// lookups match them in either kind of base database. IPv4-compatible
+    /// (`::a.b.c.d`) and IPv4-mapped (`::ffff:a.b.c.d`) lookups match them too.
+    Tree {
+        v4: PrefixTree,
+        v6: PrefixTree,
+        /// Frozen copies of the override records, registered with the GC so they
+        /// stay alive and in place from the moment they are created
+        records: BoxValue<RArray>,
+    },
+    Reader(Reader),
+}
+
+impl Overrides {
+    /// Build a tree from a Hash of networks to records for a base database of
+    /// `ip_version`
+    fn from_hash(ruby: &magnus::Ruby, hash: RHash, ip_version: u16) -> Result<Self, Error> {
+        let mut v4 = PrefixTree::default();
+        let mut v6 = PrefixTree::default();
+        let records = BoxValue::new(ruby.ary_new_capa(hash.len()));
+        hash.foreach(|network: Value, record: Value| {
+            let network = parse_network(ruby, network)?;
+            let index = records.len();
+            match network {
+                IpNetwork::V4(network) => v4.insert(
+                    u32::from(network.network()) as u128,
+                    network.prefix(),
+                    32,
+                    index,
+                ),
+                IpNetwork::V6(network) if ip_version == 6 => {
+                    v6.insert(u128::from(network.network()), network.prefix(), 128, index)
+                }
+                IpNetwork::V6(network) => {
+                    return Err(Error::new(
+                        ruby.exception_arg_error(),
+                        format!(
+                            "Cannot override IPv6 network '{}' in an IPv4-only database",
+                            network
+                        ),
+                    ))
+                }
+            }
+            records.push(frozen_copy(ruby, record)?)?;
+            Ok(ForEach::Continue)
+        })?;
+        Ok(Overrides::Tree { v4, v6, records })
+    }
+
+    /// Return the override record for `ip`, if any, and the prefix length of the
+    /// network it holds for
+    fn lookup(&self, ruby: &magnus::Ruby, ip: IpAddr) -> Result<(Option<Value>, u8), Error> {
+        match (self, ip) {
+            (Overrides::Tree { v4, records, .. }, IpAddr::V4(ip)) => {
+                let (record, prefix) = v4.lookup(u32::from(ip) as u128, 32);
+                Ok((Overrides::record(records, record)?, prefix))
+            }
+            (Overrides::Tree { v4, v6, records }, IpAddr::V6(ip)) => {
+                let (record, prefix) = v6.lookup(u128::from(ip), 128);
+                match embedded_ipv4(ip) {
+                    Some(embedded) if !v4.nodes.is_empty() => {
+                        // IPv4 networks are matched as `/96 + prefix`, and the longer
+                        // of the two matches wins
+                        let (v4_record, v4_prefix) = v4.lookup(u32::from(embedded) as u128, 32);
+                        let v4_record = v4_record.map(|(record, len)| (record, 96 + len));
+                        let record = match (record, v4_record) {
+                            (Some(v6), Some(v4)) if v4.1 <= v6.1 => Some(v6),
+                            (_, Some(v4)) => Some(v4),
+                            (v6, None) => v6,
+                        };
+                        Ok((
+                            Overrides::record(records, record)?,
+                            prefix.max(96 + v4_prefix),
+                        ))
+                    }
+                    _ => Ok((Overrides::record(records, record)?, prefix)),
+                }
+            }
+            // An IPv4-only database has nothing for IPv6 addresses
+            (Overrides::Reader(reader), IpAddr::V6(_))
+                if reader.ip_version.load(Ordering::Acquire) == 4 =>
+            {
+                Ok((None, 0))
+            }
+            (Overrides::Reader(reader), ip) => reader.lookup_record(ruby, ip),
+        }
+    }
+
+    fn record(records: &RArray, matched: Option<(usize, u8)>) -> Result<Option<Value>, Error> {
+        matched
+            .map(|(index, _)| records.entry::<Value>(index as isize))
+            .transpose()
+    }
+}
+
+/// The IPv4 address in an IPv4-mapped (`::ffff:a.b.c.d`) or IPv4-compatible
+/// (`::a.b.c.d`) address. `::` and `::1` are not treated as IPv4 addresses.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(embedded) = ip.to_ipv4_mapped() {
+        return Some(embedded);
+    }
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0, _, _] if !ip.is_unspecified() && !ip.is_loopback() => ip.to_ipv4(),
+        _ => None,
+    }
+}
+
+/// Looks addresses up in a set of overrides first, falling back to a base Reader
+#[magnus::wrap(class = "MaxMind::DB::Rust::OverlayReader", mark)]
+struct OverlayReader {
+    base: Reader,
+    overrides: Overrides,
+    merge: OverlayMerge,
+}
+
+impl DataTypeFunctions for OverlayReader {
+    fn mark(&self, marker: &Marker) {
+        self.base.mark(marker);
+        if let Overrides::Reader(reader) = &self.overrides {
+            reader.mark(marker);
+        }
+    }
+}
+
+// SAFETY: OverlayReaders are only used and dropped by Ruby threads holding the GVL,
+// which is required to unregister the BoxValue of override records.
+unsafe impl Send for OverlayReader {}
+
+impl OverlayReader {
+    /// `OverlayReader.new(base_reader, overrides, merge: :replace)`, where `overrides`
+    /// is a Hash of networks to records or another Reader
+    fn new(args: &[Value]) -> Result<Self, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(Value, Value), (), (), (), _, ()>(args)?;
+        let (base, overrides) = args.required;
+        let kw = get_kwargs::<_, (), (Option<Symbol>,), ()>(args.keywords, &[], &["merge"])?;
+        let (merge,) = kw.optional;
+        let merge = OverlayMerge::from_symbol(&ruby, merge)?;
+
+        let base = <&Reader>::try_convert(base)?.clone();
+        let overrides = if let Some(hash) = RHash::from_value(overrides) {
+            Overrides::from_hash(&ruby, hash, base.ip_version.load(Ordering::Acquire))?
+        } else if let Ok(reader) = <&Reader>::try_convert(overrides) {
+            Overrides::Reader(reader.clone())
+        } else {
+            return Err(Error::new(
+                ruby.exception_type_error(),
+                "Overrides must be a Hash or a MaxMind::DB::Rust::Reader",
+            ));
+        };
+
+        Ok(OverlayReader {
+            base,
+            overrides,
+            merge,
+        })
+    }
+
+    /// Look up `ip_address` in the overrides, then in the base database unless an
+    /// override replaces it. The prefix length is that of the network the returned
+    /// record holds for. Records are always returned deep-frozen.
+    fn lookup(&self, ruby: &magnus::Ruby, ip_address: Value) -> Result<(Value, u8), Error> {
+        let ip = self.base.parse_lookup_ip(ruby, ip_address)?;
+        let (override_record, override_prefix) = self.overrides.lookup(ruby, ip)?;
+        if let (Some(record), OverlayMerge::Replace) = (override_record, self.merge) {
+            deep_freeze(record)?;
+            return Ok((record, override_prefix));
+        }
+
+        let (base_record, base_prefix) = self.base.lookup_record(ruby, ip)?;
+        let record = match (override_record, base_record) {
+            (Some(record), Some(base)) => {
+                merge_records(ruby, base, record, self.merge == OverlayMerge::Deep)?
+            }
+            (Some(record), None) | (None, Some(record)) => record,
+            (None, None) => ruby.qnil().as_value(),
+        };
+        deep_freeze(record)?;
+        // Both networks contain the address, so the smaller one is where neither changes
+        Ok((record, override_prefix.max(base_prefix)))
+    }
+
+    /// Look up the effective record for `ip_address`
+    fn get(&self, ip_address: Value) -> Result<Value, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        let (record, _) = self.lookup(&ruby, ip_address)?;
+        Ok(record)
+    }
+
+    /// Look up the effective record for `ip_address`, returning `[record, prefix_length]`
+    fn get_with_prefix_length(&self, ip_address: Value) -> Result<RArray, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        let (record, prefix) = self.lookup(&ruby, ip_address)?;
+        let arr = ruby.ary_new_capa(2);
+        arr.push(record)?;
+        arr.push(prefix.into_value_with(&ruby))?;
+        Ok(arr)
+    }
+
+    /// Look up the effective record for each of `ip_addresses`, raising on the first
+    /// invalid address
+    fn get_many(&self, ip_addresses: RArray) -> Result<RArray, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+        let results = ruby.ary_new_capa(ip_addresses.len());
+        for ip_address in ip_addresses.to_vec::<Value>()? {
+            let (record, _) = self.lookup(&ruby, ip_address)?;
+            results.push(record)?;
+        }
+        Ok(results)
+    }
+
+    /// Metadata of the base database
+    fn metadata(&self) -> Result<Metadata, Error> {
+        self.base.metadata()
+    }
+}
+
+/// Deep-copy an override record and freeze the copy, so neither callers changing a
+/// record returned by a lookup nor changes to the original affect later lookups
+fn frozen_copy(ruby: &magnus::Ruby, value: Value) -> Result<Value, Error> {
+    let copy = if let Some(hash) = RHash::from_value(value) {
+        let copy = ruby.hash_new_capa(hash.len());
+        hash.foreach(|key: Value, value: Value| {
+            copy.aset(frozen_copy(ruby, key)?, frozen_copy(ruby, value)?)?;
+            Ok(ForEach::Continue)
+        })?;
+        copy.as_value()
+    } else if let Some(array) = RArray::from_value(value) {
+        let copy = ruby.ary_new_capa(array.len());
+        for value in array.to_vec::<Value>()? {
+            copy.push(frozen_copy(ruby, value)?)?;
+        }
+        copy.as_value()
+    } else if RString::from_value(value).is_some() {
+        value.funcall::<_, _, Value>("dup", ())?
+    } else {
+        // Integers, Floats, Symbols, true, and false are already immutable
+        return Ok(value);
+    };
+    copy.freeze();
+    Ok(copy)
+}
+
+/// Freeze `value` and the Hashes, Arrays, and Strings in it. Frozen values are left
+/// alone: override copies, cached records, and records decoded with `freeze: true`
+/// are already frozen throughout.
+fn deep_freeze(value: Value) -> Result<(), Error> {
+    if value.is_frozen() {
+        return Ok(());
+    }
+    if let Some(hash) = RHash::from_value(value) {
+        hash.foreach(|_: Value, value: Value| {
+            deep_freeze(value)?;
+            Ok(ForEach::Continue)
+        })?;
+    } else if let Some(array) = RArray::from_value(value) {
+        for value in array.to_vec::<Value>()? {
+            deep_freeze(value)?;
+        }
+    }
+    value.freeze();
+    Ok(())
+}
+
+/// Merge the `overlay` Hash into a copy of the `base` Hash. Anything other than two
+/// Hashes is replaced by `overlay`.
+fn merge_records(
+    ruby: &magnus::Ruby,
+    base: Value,
+    overlay: Value,
+    deep: bool,
+) -> Result<Value, Error> {
+    let (Some(base), Some(overlay)) = (RHash::from_value(base), RHash::from_value(overlay)) else {
+        return Ok(overlay);
+    };
+    let merged = ruby.hash_new_capa(base.len() + overlay.len());
+    base.foreach(|key: Value, value: Value| {
+        merged.aset(key, value)?;
+        Ok(ForEach::Continue)
+    })?;
+    overlay.foreach(|key: Value, value: Value| {
+        let value = match merged.get(key) {
+            Some(existing) if deep => merge_records(ruby, existing, value, true)?,
+            _ => value,
+        };
+        merged.aset(key, value)?;
+        Ok(ForEach::Continue)
+    })?;
+    Ok(merged.as_value())
+}
+
+pub(crate) fn init(ruby: &magnus::Ruby, rust: RModule) -> Result<(), Error> {
+    let overlay_class = rust.define_class("OverlayReader", ruby.class_object())?;
+    overlay_class.define_singleton_method("new", magnus::function!(OverlayReader::new, -1))?;
+    overlay_class.define_method("get", magnus::method!(OverlayReader::get, 1))?;
+    overlay_class.define_method(
+        "get_with_prefix_length",
+        magnus::method!(OverlayReader::get_with_prefix_length, 1),
+    )?;
+    overlay_class.define_method("get_many", magnus::method!(OverlayReader::get_many, 1))?;
+    overlay_class.define_method("metadata", magnus::method!(OverlayReader::metadata, 0))?;
+    Ok(())
+}
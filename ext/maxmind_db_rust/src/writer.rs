@@ -0,0 +1,1259 @@
+//! Building MaxMind DB files from Ruby data: `MaxMind::DB::Rust::Writer`
+
+use crate::{interned_key, parse_network, without_gvl};
+use ipnetwork::IpNetwork;
+use magnus::{
+    encoding::EncodingCapable, error::Error, prelude::*, r_hash::ForEach, scan_args::get_kwargs,
+    scan_args::scan_args, Float, Integer, IntoValue, RArray, RHash, RModule, RString, Symbol,
+    TryConvert, Value,
+};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt, fs,
+    hash::Hasher,
+    io::{self, Write as _},
+    mem, ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Marks the start of the metadata section at the end of the file
+const METADATA_START_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// Zero bytes between the search tree and the data section
+const DATA_SECTION_SEPARATOR_SIZE: usize = 16;
+
+/// Largest size a control byte can encode, for Strings, Maps, and Arrays
+const MAX_VALUE_SIZE: usize = 65_821 + (1 << 24) - 1;
+
+/// Numbers the temporary files `Writer#write` renames into place, so writes from
+/// different threads or Writers to the same path never share one
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Most Hashes and Arrays a value may be nested in, the limit readers apply when
+/// decoding. Ruby values are checked when they are converted and values read from a
+/// database are limited by its decoder, so encoding and converting back to Ruby never
+/// recurse deeper.
+const MAXIMUM_DATA_STRUCTURE_DEPTH: usize = 512;
+
+/// A value that can be stored in the data section
+#[derive(Clone, PartialEq)]
+pub(crate) enum WriterValue {
+    /// Entries in insertion order
+    Map(Vec<(String, WriterValue)>),
+    Array(Vec<WriterValue>),
+    String(String),
+    Bytes(Vec<u8>),
+    Double(f64),
+    /// Only read from existing databases; Ruby Floats are stored as doubles
+    Float(f32),
+    Uint16(u16),
+    Uint32(u32),
+    Uint64(u64),
+    Uint128(u128),
+    Int32(i32),
+    Boolean(bool),
+}
+
+impl WriterValue {
+    /// Convert a Ruby Hash, Array, String, Symbol, Integer, Float, true, or false.
+    /// Binary Strings become bytes, and Integers use the smallest type that holds them.
+    fn from_ruby(ruby: &magnus::Ruby, value: Value) -> Result<Self, Error> {
+        WriterValue::from_ruby_nested(ruby, value, 0)
+    }
+
+    /// Convert `value`, which is nested in `depth` Hashes and Arrays
+    fn from_ruby_nested(ruby: &magnus::Ruby, value: Value, depth: usize) -> Result<Self, Error> {
+        let is_container =
+            RHash::from_value(value).is_some() || RArray::from_value(value).is_some();
+        if is_container && depth >= MAXIMUM_DATA_STRUCTURE_DEPTH {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!(
+                    "Values can't be nested more than {} levels deep (or contain themselves)",
+                    MAXIMUM_DATA_STRUCTURE_DEPTH
+                ),
+            ));
+        }
+        if let Some(hash) = RHash::from_value(value) {
+            let mut entries = Vec::with_capacity(hash.len());
+            hash.foreach(|key: Value, value: Value| {
+                let key = map_key(ruby, key)?;
+                entries.push((key, WriterValue::from_ruby_nested(ruby, value, depth + 1)?));
+                Ok(ForEach::Continue)
+            })?;
+            check_size(ruby, entries.len())?;
+            return Ok(WriterValue::Map(entries));
+        }
+        if let Some(array) = RArray::from_value(value) {
+            check_size(ruby, array.len())?;
+            return array
+                .to_vec::<Value>()?
+                .into_iter()
+                .map(|value| WriterValue::from_ruby_nested(ruby, value, depth + 1))
+                .collect::<Result<_, _>>()
+                .map(WriterValue::Array);
+        }
+        if let Some(string) = RString::from_value(value) {
+            if string.enc_get() == ruby.ascii8bit_encindex() {
+                // SAFETY: the bytes are copied before any Ruby code can run
+                let bytes = unsafe { string.as_slice() }.to_vec();
+                check_size(ruby, bytes.len())?;
+                return Ok(WriterValue::Bytes(bytes));
+            }
+            let string = string.to_string()?;
+            check_size(ruby, string.len())?;
+            return Ok(WriterValue::String(string));
+        }
+        if let Some(symbol) = Symbol::from_value(value) {
+            return Ok(WriterValue::String(symbol.name()?.into_owned()));
+        }
+        if let Some(integer) = Integer::from_value(value) {
+            return integer_value(ruby, integer);
+        }
+        if let Some(float) = Float::from_value(value) {
+            return Ok(WriterValue::Double(float.to_f64()));
+        }
+        if value.is_kind_of(ruby.class_true_class()) {
+            return Ok(WriterValue::Boolean(true));
+        }
+        if value.is_kind_of(ruby.class_false_class()) {
+            return Ok(WriterValue::Boolean(false));
+        }
+        if value.is_nil() {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "nil can't be stored in a MaxMind DB",
+            ));
+        }
+        Err(Error::new(
+            ruby.exception_type_error(),
+            format!(
+                "Unsupported value type: {} (expected Hash, Array, String, Symbol, Integer, Float, true, or false)",
+                unsafe { value.classname() }
+            ),
+        ))
+    }
+
+    /// Convert to the Ruby objects `Reader#get` returns for the value
+    pub(crate) fn to_ruby(&self, ruby: &magnus::Ruby) -> Result<Value, Error> {
+        Ok(match self {
+            WriterValue::Map(entries) => {
+                let hash = ruby.hash_new_capa(entries.len());
+                for (key, value) in entries {
+                    let key = interned_key(ruby, key).unwrap_or_else(|| {
+                        let key = ruby.str_new(key);
+                        key.freeze();
+                        key.as_value()
+                    });
+                    hash.aset(key, value.to_ruby(ruby)?)?;
+                }
+                hash.as_value()
+            }
+            WriterValue::Array(values) => {
+                let array = ruby.ary_new_capa(values.len());
+                for value in values {
+                    array.push(value.to_ruby(ruby)?)?;
+                }
+                array.as_value()
+            }
+            WriterValue::String(string) => ruby.str_new(string).as_value(),
+            WriterValue::Bytes(bytes) => ruby.str_from_slice(bytes).as_value(),
+            WriterValue::Double(n) => n.into_value_with(ruby),
+            WriterValue::Float(n) => (*n as f64).into_value_with(ruby),
+            WriterValue::Uint16(n) => n.into_value_with(ruby),
+            WriterValue::Uint32(n) => n.into_value_with(ruby),
+            WriterValue::Uint64(n) => n.into_value_with(ruby),
+            WriterValue::Uint128(n) => n.into_value_with(ruby),
+            WriterValue::Int32(n) => n.into_value_with(ruby),
+            WriterValue::Boolean(b) => b.into_value_with(ruby),
+        })
+    }
+
+    /// Keep only the top-level `fields` of a Map, leaving an empty Map if it has
+    /// none of them. Other values are kept whole.
+    pub(crate) fn project(self, fields: &[String]) -> WriterValue {
+        match self {
+            WriterValue::Map(mut entries) => {
+                entries.retain(|(key, _)| fields.contains(key));
+                WriterValue::Map(entries)
+            }
+            value => value,
+        }
+    }
+}
+
+/// Decodes records from an existing database, keeping each value's stored type
+impl<'de> Deserialize<'de> for WriterValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(WriterValueVisitor)
+    }
+}
+
+struct WriterValueVisitor;
+
+impl<'de> Visitor<'de> for WriterValueVisitor {
+    type Value = WriterValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a MaxMind DB value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(WriterValue::Boolean(v))
+    }
+
+    fn visit_i32<E>(self, v: i32) -> Result<Self::Value, E> {
+        Ok(WriterValue::Int32(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        i32::try_from(v)
+            .map(WriterValue::Int32)
+            .map_err(|_| E::custom(format!("int32 out of range: {}", v)))
+    }
+
+    fn visit_u16<E>(self, v: u16) -> Result<Self::Value, E> {
+        Ok(WriterValue::Uint16(v))
+    }
+
+    fn visit_u32<E>(self, v: u32) -> Result<Self::Value, E> {
+        Ok(WriterValue::Uint32(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(WriterValue::Uint64(v))
+    }
+
+    fn visit_u128<E>(self, v: u128) -> Result<Self::Value, E> {
+        Ok(WriterValue::Uint128(v))
+    }
+
+    fn visit_f32<E>(self, v: f32) -> Result<Self::Value, E> {
+        Ok(WriterValue::Float(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(WriterValue::Double(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(WriterValue::String(v.to_owned()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Self::Value, E> {
+        Ok(WriterValue::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(WriterValue::Bytes(v.to_vec()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(WriterValue::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(WriterValue::Map(entries))
+    }
+}
+
+fn map_key(ruby: &magnus::Ruby, key: Value) -> Result<String, Error> {
+    if let Some(key) = RString::from_value(key) {
+        return key.to_string();
+    }
+    if let Some(key) = Symbol::from_value(key) {
+        return Ok(key.name()?.into_owned());
+    }
+    Err(Error::new(
+        ruby.exception_type_error(),
+        "Map keys must be Strings or Symbols",
+    ))
+}
+
+fn integer_value(ruby: &magnus::Ruby, integer: Integer) -> Result<WriterValue, Error> {
+    if let Ok(n) = integer.to_i64() {
+        return match n {
+            n if n < 0 => i32::try_from(n).map(WriterValue::Int32).map_err(|_| {
+                Error::new(
+                    ruby.exception_range_error(),
+                    format!("Integer {} is too small for a MaxMind DB int32", n),
+                )
+            }),
+            n if n <= u16::MAX as i64 => Ok(WriterValue::Uint16(n as u16)),
+            n if n <= u32::MAX as i64 => Ok(WriterValue::Uint32(n as u32)),
+            n => Ok(WriterValue::Uint64(n as u64)),
+        };
+    }
+    u128::try_convert(integer.as_value())
+        .map(|n| match u64::try_from(n) {
+            Ok(n) => WriterValue::Uint64(n),
+            Err(_) => WriterValue::Uint128(n),
+        })
+        .map_err(|_| {
+            Error::new(
+                ruby.exception_range_error(),
+                "Integer is out of range for a MaxMind DB (int32 to uint128)",
+            )
+        })
+}
+
+fn check_size(ruby: &magnus::Ruby, size: usize) -> Result<(), Error> {
+    if size > MAX_VALUE_SIZE {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            format!(
+                "Value is too large for a MaxMind DB ({} > {})",
+                size, MAX_VALUE_SIZE
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Serializes values in the MaxMind DB data section format.
+///
+/// Each distinct value is written once. Repeats of a value written before, including
+/// Maps, Arrays, and Strings nested inside other values and map keys, are written as
+/// pointers to it when the pointer is smaller.
+#[derive(Default)]
+struct DataSection {
+    bytes: Vec<u8>,
+    /// Offset of each value written, keyed by a hash of its encoding. Values whose
+    /// hashes collide with an earlier value are written again rather than shared.
+    offsets: HashMap<u64, usize>,
+}
+
+/// A value encoded on its own, before its position in the data section is known
+#[derive(Default)]
+struct Encoded {
+    bytes: Vec<u8>,
+    /// Position and length of the nested values in `bytes` that repeats can point at
+    parts: Vec<(usize, usize)>,
+}
+
+impl DataSection {
+    /// Write `value`, or find an identical value already written, returning its offset
+    fn store(&mut self, value: &WriterValue) -> usize {
+        let encoded = self.encode(value);
+        if let Some(offset) = self.find(&encoded.bytes) {
+            return offset;
+        }
+        let offset = self.bytes.len();
+        self.bytes.extend_from_slice(&encoded.bytes);
+        for (position, len) in encoded.parts {
+            self.remember(offset + position, len);
+        }
+        self.remember(offset, encoded.bytes.len());
+        offset
+    }
+
+    /// Offset of a value already written with the encoding `bytes`
+    fn find(&self, bytes: &[u8]) -> Option<usize> {
+        let &offset = self.offsets.get(&hash_bytes(bytes))?;
+        (self.bytes.get(offset..offset + bytes.len()) == Some(bytes)).then_some(offset)
+    }
+
+    /// Let repeats point at the `len` bytes written at `offset`, unless a value
+    /// with the same hash was written first
+    fn remember(&mut self, offset: usize, len: usize) {
+        let hash = hash_bytes(&self.bytes[offset..offset + len]);
+        self.offsets.entry(hash).or_insert(offset);
+    }
+
+    fn encode(&self, value: &WriterValue) -> Encoded {
+        let mut encoded = Encoded::default();
+        match value {
+            WriterValue::Map(entries) => {
+                write_control(&mut encoded.bytes, 7, entries.len());
+                for (key, value) in entries {
+                    let mut key_bytes = Vec::with_capacity(key.len() + 1);
+                    write_control(&mut key_bytes, 2, key.len());
+                    key_bytes.extend_from_slice(key.as_bytes());
+                    self.append(
+                        &mut encoded,
+                        Encoded {
+                            bytes: key_bytes,
+                            parts: Vec::new(),
+                        },
+                    );
+                    self.append(&mut encoded, self.encode(value));
+                }
+            }
+            WriterValue::Array(values) => {
+                write_control(&mut encoded.bytes, 11, values.len());
+                for value in values {
+                    self.append(&mut encoded, self.encode(value));
+                }
+            }
+            scalar => write_scalar(&mut encoded.bytes, scalar),
+        }
+        encoded
+    }
+
+    /// Append `child` to `parent`, or a pointer to an identical value already written
+    fn append(&self, parent: &mut Encoded, child: Encoded) {
+        if let Some(offset) = self.find(&child.bytes) {
+            if pointer_size(offset) < child.bytes.len() {
+                write_pointer(&mut parent.bytes, offset);
+                return;
+            }
+        }
+        let start = parent.bytes.len();
+        parent.parts.extend(
+            child
+                .parts
+                .iter()
+                .map(|&(position, len)| (start + position, len)),
+        );
+        // No pointer is smaller than two bytes
+        if child.bytes.len() > 2 {
+            parent.parts.push((start, child.bytes.len()));
+        }
+        parent.bytes.extend_from_slice(&child.bytes);
+    }
+
+    /// Size of `value` written in full, without pointers
+    fn full_size(value: &WriterValue) -> usize {
+        DataSection::default().encode(value).bytes.len()
+    }
+}
+
+/// Hash an encoded value for `DataSection::offsets`
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Append a value that isn't a Map or Array
+fn write_scalar(out: &mut Vec<u8>, value: &WriterValue) {
+    match value {
+        WriterValue::Map(_) | WriterValue::Array(_) => {
+            unreachable!("containers are encoded by DataSection::encode")
+        }
+        WriterValue::String(string) => {
+            write_control(out, 2, string.len());
+            out.extend_from_slice(string.as_bytes());
+        }
+        WriterValue::Bytes(bytes) => {
+            write_control(out, 4, bytes.len());
+            out.extend_from_slice(bytes);
+        }
+        WriterValue::Double(n) => {
+            write_control(out, 3, 8);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+        WriterValue::Float(n) => {
+            write_control(out, 15, 4);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+        WriterValue::Uint16(n) => write_unsigned(out, 5, &n.to_be_bytes()),
+        WriterValue::Uint32(n) => write_unsigned(out, 6, &n.to_be_bytes()),
+        WriterValue::Uint64(n) => write_unsigned(out, 9, &n.to_be_bytes()),
+        WriterValue::Uint128(n) => write_unsigned(out, 10, &n.to_be_bytes()),
+        WriterValue::Int32(n) if *n < 0 => {
+            write_control(out, 8, 4);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+        WriterValue::Int32(n) => write_unsigned(out, 8, &n.to_be_bytes()),
+        WriterValue::Boolean(b) => write_control(out, 14, *b as usize),
+    }
+}
+
+/// Append a big-endian integer without its leading zero bytes
+fn write_unsigned(out: &mut Vec<u8>, type_num: u8, bytes: &[u8]) {
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    write_control(out, type_num, bytes.len() - skip);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+/// Append the control byte(s) for a value of `type_num` and `size`
+fn write_control(out: &mut Vec<u8>, type_num: u8, size: usize) {
+    let first = if type_num <= 7 { type_num << 5 } else { 0 };
+    let (size_bits, extra): (u8, Vec<u8>) = match size {
+        0..=28 => (size as u8, Vec::new()),
+        29..=284 => (29, vec![(size - 29) as u8]),
+        285..=65_820 => (30, ((size - 285) as u16).to_be_bytes().to_vec()),
+        _ => (31, ((size - 65_821) as u32).to_be_bytes()[1..].to_vec()),
+    };
+    out.push(first | size_bits);
+    if type_num > 7 {
+        out.push(type_num - 7);
+    }
+    out.extend_from_slice(&extra);
+}
+
+/// Size of a pointer to `offset`, including its control byte
+fn pointer_size(offset: usize) -> usize {
+    match offset {
+        0..=2_047 => 2,
+        2_048..=526_335 => 3,
+        526_336..=134_744_063 => 4,
+        _ => 5,
+    }
+}
+
+/// Append a pointer to `offset` in the data section
+fn write_pointer(out: &mut Vec<u8>, offset: usize) {
+    match offset {
+        0..=2_047 => out.extend_from_slice(&[0x20 | (offset >> 8) as u8, offset as u8]),
+        2_048..=526_335 => {
+            let value = offset - 2_048;
+            out.extend_from_slice(&[0x28 | (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+        }
+        526_336..=134_744_063 => {
+            let value = offset - 526_336;
+            out.extend_from_slice(&[
+                0x30 | (value >> 24) as u8,
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            ]);
+        }
+        _ => {
+            out.push(0x38);
+            out.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+    }
+}
+
+/// Error from building or writing a database, raised once the GVL is held again
+enum WriteError {
+    TooLarge(String),
+    Io(String),
+}
+
+/// Create a temporary file next to `path` that no other write is using. A name left
+/// behind by a crashed process is skipped rather than overwritten.
+fn create_temp_file(path: &str) -> io::Result<(String, fs::File)> {
+    loop {
+        let count = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let temp_path = format!("{}.tmp-{}-{}", path, std::process::id(), count);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sizes reported by `Writer#write`
+struct WriteStats {
+    node_count: usize,
+    data_section_size: usize,
+    /// Size the data section would have if every network's record were written in full
+    undeduplicated_size: usize,
+    file_size: usize,
+}
+
+/// How `Writer#insert` combines new data with data already stored in the network
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MergeStrategy {
+    /// The new data replaces the existing data
+    Replace,
+    /// Top-level keys of the new Hash are added to the existing Hash
+    Shallow,
+    /// Hashes are merged recursively
+    Deep,
+    /// Existing data is kept, and the new data only fills addresses without data
+    Keep,
+}
+
+impl MergeStrategy {
+    fn from_symbol(ruby: &magnus::Ruby, symbol: Option<Symbol>) -> Result<Self, Error> {
+        let Some(symbol) = symbol else {
+            return Ok(MergeStrategy::Replace);
+        };
+        match &*symbol.name()? {
+            "replace" => Ok(MergeStrategy::Replace),
+            "shallow" => Ok(MergeStrategy::Shallow),
+            "deep" => Ok(MergeStrategy::Deep),
+            "keep" => Ok(MergeStrategy::Keep),
+            other => Err(Error::new(
+                ruby.exception_arg_error(),
+                format!(
+                    "Unsupported merge value: {} (expected :replace, :shallow, :deep, or :keep)",
+                    other
+                ),
+            )),
+        }
+    }
+}
+
+/// Combine `existing` with `new` using a Hash-merging strategy. Anything other than
+/// two Hashes is replaced by `new`.
+fn merge_values(existing: &WriterValue, new: &WriterValue, deep: bool) -> WriterValue {
+    let (WriterValue::Map(existing), WriterValue::Map(new)) = (existing, new) else {
+        return new.clone();
+    };
+    let mut merged = existing.clone();
+    for (key, value) in new {
+        match merged.iter_mut().find(|(k, _)| k == key) {
+            Some((_, current)) if deep => *current = merge_values(current, value, true),
+            Some((_, current)) => *current = value.clone(),
+            None => merged.push((key.clone(), value.clone())),
+        }
+    }
+    WriterValue::Map(merged)
+}
+
+/// A node of the search tree being built
+#[derive(Clone)]
+enum Node {
+    Empty,
+    /// Index into `WriterState::values`
+    Data(usize),
+    Branch(Box<[Node; 2]>),
+    /// Written as a pointer to the `::/96` node, where IPv4 addresses are stored
+    Alias,
+}
+
+/// IPv6 networks that embed IPv4 addresses: IPv4-mapped addresses and 6to4. IPv6
+/// databases store them as aliases of `::/96` so their lookups find the IPv4 data.
+pub(crate) const IPV4_ALIASES: [(u128, u8); 2] = [(0xffff << 32, 96), (0x2002 << 112, 16)];
+
+/// Whether `address`/`prefix` in an IPv6 tree is within one of `IPV4_ALIASES`
+fn in_ipv4_alias(address: u128, prefix: u8) -> bool {
+    IPV4_ALIASES.iter().any(|&(alias, alias_prefix)| {
+        prefix >= alias_prefix && (address ^ alias) >> (128 - alias_prefix) == 0
+    })
+}
+
+/// Database-wide settings given to `Writer.new`
+struct WriterOptions {
+    database_type: String,
+    ip_version: u16,
+    record_size: u16,
+    languages: Vec<String>,
+    description: Vec<(String, String)>,
+    alias_ipv6_to_ipv4: bool,
+}
+
+impl WriterOptions {
+    /// Whether the search tree gets `IPV4_ALIASES`
+    fn aliases(&self) -> bool {
+        self.ip_version == 6 && self.alias_ipv6_to_ipv4
+    }
+}
+
+struct WriterState {
+    root: Node,
+    values: Vec<WriterValue>,
+    /// Length of `values` when it was last compacted
+    compacted_len: usize,
+    /// Whether the tree keeps `IPV4_ALIASES` as `Node::Alias`
+    aliases: bool,
+}
+
+impl WriterState {
+    fn new(aliases: bool) -> Self {
+        let mut state = WriterState {
+            root: Node::Empty,
+            values: Vec::new(),
+            compacted_len: 0,
+            aliases,
+        };
+        state.add_aliases();
+        state
+    }
+
+    /// Store `value` for `network`, combining it with data already stored within the
+    /// network according to `merge`. Networks are given as the address bits and prefix
+    /// length in a tree of `depth`.
+    fn insert(
+        &mut self,
+        address: u128,
+        prefix: u8,
+        depth: u8,
+        value: WriterValue,
+        merge: MergeStrategy,
+    ) {
+        let id = self.values.len();
+        self.values.push(value);
+
+        // Each existing value is merged with the new one once, however many
+        // networks it is stored for
+        let mut merged = HashMap::new();
+        update_network(
+            &mut self.root,
+            address,
+            prefix,
+            depth,
+            0,
+            &mut self.values,
+            &mut |node, values| merge_into(node, id, merge, values, &mut merged),
+        );
+        self.add_aliases();
+        self.compact();
+    }
+
+    /// Remove the data for every address in `network`
+    fn remove(&mut self, address: u128, prefix: u8, depth: u8) {
+        update_network(
+            &mut self.root,
+            address,
+            prefix,
+            depth,
+            0,
+            &mut self.values,
+            &mut |node, _| *node = Node::Empty,
+        );
+        self.add_aliases();
+        self.compact();
+    }
+
+    /// Put back any alias that data stored for a network containing it replaced
+    fn add_aliases(&mut self) {
+        if !self.aliases {
+            return;
+        }
+        for (address, prefix) in IPV4_ALIASES {
+            update_network(
+                &mut self.root,
+                address,
+                prefix,
+                128,
+                0,
+                &mut self.values,
+                &mut |node, _| *node = Node::Alias,
+            );
+        }
+    }
+
+    /// Drop the values no longer stored for any network once `values` has doubled
+    /// since it was last compacted, renumbering the rest
+    fn compact(&mut self) {
+        if self.values.len() < 2 * self.compacted_len.max(512) {
+            return;
+        }
+        let mut ids = vec![None; self.values.len()];
+        let mut values = Vec::new();
+        renumber(&mut self.root, &mut ids, &mut self.values, &mut values);
+        self.values = values;
+        self.compacted_len = self.values.len();
+    }
+
+    /// Serialize the database: the search tree, the data section, and the metadata
+    fn build(&self, options: &WriterOptions) -> Result<(Vec<u8>, WriteStats), WriteError> {
+        // The search tree needs at least one node
+        let expanded;
+        let root: &[Node; 2] = match &self.root {
+            Node::Branch(children) => children,
+            leaf => {
+                expanded = Box::new([leaf.clone(), leaf.clone()]);
+                &expanded
+            }
+        };
+
+        // Number the nodes breadth first, so the root is node 0
+        let mut nodes: Vec<&[Node; 2]> = vec![root];
+        let mut i = 0;
+        while i < nodes.len() {
+            for child in nodes[i].iter() {
+                if let Node::Branch(children) = child {
+                    nodes.push(children);
+                }
+            }
+            i += 1;
+        }
+        let node_count = nodes.len() as u64;
+
+        // Aliases point at the node for `::/96`, or share its record if it's a leaf
+        let mut ipv4 = &root[0];
+        for _ in 1..96 {
+            match ipv4 {
+                Node::Branch(children) => ipv4 = &children[0],
+                _ => break,
+            }
+        }
+        let ipv4_node = match ipv4 {
+            Node::Branch(children) => nodes.iter().position(|node| ptr::eq(*node, &**children)),
+            _ => None,
+        };
+
+        // Records are computed in the same order the nodes were numbered
+        let mut data = DataSection::default();
+        // Offset and full size of each value, once it has been written
+        let mut written: Vec<Option<(usize, usize)>> = vec![None; self.values.len()];
+        let mut undeduplicated_size = 0;
+        let mut leaf_record = |leaf: &Node| match leaf {
+            Node::Data(id) => {
+                let value = &self.values[*id];
+                let (offset, full_size) = *written[*id]
+                    .get_or_insert_with(|| (data.store(value), DataSection::full_size(value)));
+                undeduplicated_size += full_size;
+                node_count + DATA_SECTION_SEPARATOR_SIZE as u64 + offset as u64
+            }
+            _ => node_count,
+        };
+        let mut next_node = 1;
+        let mut records = Vec::with_capacity(nodes.len() * 2);
+        for node in &nodes {
+            for child in node.iter() {
+                records.push(match child {
+                    Node::Branch(_) => {
+                        next_node += 1;
+                        next_node - 1
+                    }
+                    Node::Alias => match ipv4_node {
+                        Some(ipv4_node) => ipv4_node as u64,
+                        None => leaf_record(ipv4),
+                    },
+                    leaf => leaf_record(leaf),
+                });
+            }
+        }
+
+        let max_record = node_count + DATA_SECTION_SEPARATOR_SIZE as u64 + data.bytes.len() as u64;
+        if max_record >= 1 << options.record_size {
+            return Err(WriteError::TooLarge(format!(
+                "Database is too large for {}-bit records; use a larger record_size",
+                options.record_size
+            )));
+        }
+
+        let tree_size = nodes.len() * options.record_size as usize / 4;
+        let mut out =
+            Vec::with_capacity(tree_size + DATA_SECTION_SEPARATOR_SIZE + data.bytes.len() + 512);
+        for pair in records.chunks_exact(2) {
+            let (left, right) = (pair[0], pair[1]);
+            match options.record_size {
+                24 => {
+                    out.extend_from_slice(&(left as u32).to_be_bytes()[1..]);
+                    out.extend_from_slice(&(right as u32).to_be_bytes()[1..]);
+                }
+                28 => {
+                    out.extend_from_slice(&(left as u32).to_be_bytes()[1..]);
+                    out.push((((left >> 24) as u8) << 4) | ((right >> 24) as u8 & 0x0f));
+                    out.extend_from_slice(&(right as u32).to_be_bytes()[1..]);
+                }
+                _ => {
+                    out.extend_from_slice(&(left as u32).to_be_bytes());
+                    out.extend_from_slice(&(right as u32).to_be_bytes());
+                }
+            }
+        }
+        out.extend_from_slice(&[0; DATA_SECTION_SEPARATOR_SIZE]);
+        out.extend_from_slice(&data.bytes);
+
+        out.extend_from_slice(METADATA_START_MARKER);
+        // Pointers in the metadata would be relative to the metadata, so it is
+        // written in full
+        let metadata = DataSection::default().encode(&options.metadata(node_count as u32));
+        out.extend_from_slice(&metadata.bytes);
+
+        let stats = WriteStats {
+            node_count: nodes.len(),
+            data_section_size: data.bytes.len(),
+            undeduplicated_size,
+            file_size: out.len(),
+        };
+        Ok((out, stats))
+    }
+}
+
+/// Descend to the node for `address`/`prefix`, splitting leaves on the way, and
+/// apply `update` to it. Branches whose halves end up identical are collapsed on the
+/// way back up, so adjacent networks with the same data are stored as one.
+fn update_network(
+    node: &mut Node,
+    address: u128,
+    prefix: u8,
+    depth: u8,
+    bit: u8,
+    values: &mut Vec<WriterValue>,
+    update: &mut impl FnMut(&mut Node, &mut Vec<WriterValue>),
+) {
+    if bit == prefix {
+        update(node, values);
+    } else {
+        if !matches!(node, Node::Branch(_)) {
+            let leaf = node.clone();
+            *node = Node::Branch(Box::new([leaf.clone(), leaf]));
+        }
+        let Node::Branch(children) = node else {
+            unreachable!("node was just made a branch");
+        };
+        let side = ((address >> (depth - 1 - bit)) & 1) as usize;
+        update_network(
+            &mut children[side],
+            address,
+            prefix,
+            depth,
+            bit + 1,
+            values,
+            update,
+        );
+    }
+    collapse(node, values);
+}
+
+/// Apply `merge` to every leaf under `node`, storing the value `id`
+fn merge_into(
+    node: &mut Node,
+    id: usize,
+    merge: MergeStrategy,
+    values: &mut Vec<WriterValue>,
+    merged: &mut HashMap<usize, usize>,
+) {
+    match node {
+        Node::Alias => {}
+        _ if merge == MergeStrategy::Replace => *node = Node::Data(id),
+        Node::Empty => *node = Node::Data(id),
+        Node::Data(_) if merge == MergeStrategy::Keep => {}
+        Node::Data(existing) => {
+            let existing = *existing;
+            let merged_id = *merged.entry(existing).or_insert_with(|| {
+                let value =
+                    merge_values(&values[existing], &values[id], merge == MergeStrategy::Deep);
+                values.push(value);
+                values.len() - 1
+            });
+            *node = Node::Data(merged_id);
+        }
+        Node::Branch(children) => {
+            for child in children.iter_mut() {
+                merge_into(child, id, merge, values, merged);
+            }
+            collapse(node, values);
+        }
+    }
+}
+
+/// Move the values stored under `node` from `old` to `new`, updating their ids.
+/// `ids` maps the ids of values already moved to their new ids.
+fn renumber(
+    node: &mut Node,
+    ids: &mut [Option<usize>],
+    old: &mut [WriterValue],
+    new: &mut Vec<WriterValue>,
+) {
+    match node {
+        Node::Data(id) => {
+            let old_id = *id;
+            *id = *ids[old_id].get_or_insert_with(|| {
+                new.push(mem::replace(&mut old[old_id], WriterValue::Boolean(false)));
+                new.len() - 1
+            });
+        }
+        Node::Branch(children) => {
+            for child in children.iter_mut() {
+                renumber(child, ids, old, new);
+            }
+        }
+        Node::Empty | Node::Alias => {}
+    }
+}
+
+/// Replace `node` with one of its halves if both are leaves with the same data
+fn collapse(node: &mut Node, values: &[WriterValue]) {
+    let Node::Branch(children) = node else {
+        return;
+    };
+    let same = match &**children {
+        [Node::Empty, Node::Empty] => true,
+        [Node::Data(left), Node::Data(right)] => left == right || values[*left] == values[*right],
+        _ => false,
+    };
+    if same {
+        let leaf = children[0].clone();
+        *node = leaf;
+    }
+}
+
+impl WriterOptions {
+    fn metadata(&self, node_count: u32) -> WriterValue {
+        let build_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let string = |s: &str| WriterValue::String(s.to_string());
+        WriterValue::Map(vec![
+            ("binary_format_major_version".into(), WriterValue::Uint16(2)),
+            ("binary_format_minor_version".into(), WriterValue::Uint16(0)),
+            ("build_epoch".into(), WriterValue::Uint64(build_epoch)),
+            ("database_type".into(), string(&self.database_type)),
+            (
+                "description".into(),
+                WriterValue::Map(
+                    self.description
+                        .iter()
+                        .map(|(language, text)| (language.clone(), string(text)))
+                        .collect(),
+                ),
+            ),
+            ("ip_version".into(), WriterValue::Uint16(self.ip_version)),
+            (
+                "languages".into(),
+                WriterValue::Array(self.languages.iter().map(|l| string(l)).collect()),
+            ),
+            ("node_count".into(), WriterValue::Uint32(node_count)),
+            ("record_size".into(), WriterValue::Uint16(self.record_size)),
+        ])
+    }
+}
+
+/// Builds a MaxMind DB in memory from networks and Ruby values and writes it to a file
+#[magnus::wrap(class = "MaxMind::DB::Rust::Writer")]
+pub(crate) struct Writer {
+    options: WriterOptions,
+    state: Mutex<WriterState>,
+}
+
+impl Writer {
+    /// `Writer.new(database_type:, ip_version: 6, record_size: 28, languages: [],
+    /// description: {}, alias_ipv6_to_ipv4: true)`
+    fn new(args: &[Value]) -> Result<Self, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(), (), (), (), RHash, ()>(args)?;
+        let kw = get_kwargs::<
+            _,
+            (String,),
+            (
+                Option<u16>,
+                Option<u16>,
+                Option<Vec<String>>,
+                Option<RHash>,
+                Option<bool>,
+            ),
+            (),
+        >(
+            args.keywords,
+            &["database_type"],
+            &[
+                "ip_version",
+                "record_size",
+                "languages",
+                "description",
+                "alias_ipv6_to_ipv4",
+            ],
+        )?;
+        let (database_type,) = kw.required;
+        let (ip_version, record_size, languages, description, alias_ipv6_to_ipv4) = kw.optional;
+
+        let ip_version = ip_version.unwrap_or(6);
+        if ip_version != 4 && ip_version != 6 {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("Unsupported ip_version: {} (expected 4 or 6)", ip_version),
+            ));
+        }
+        let record_size = record_size.unwrap_or(28);
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!(
+                    "Unsupported record_size: {} (expected 24, 28, or 32)",
+                    record_size
+                ),
+            ));
+        }
+        let description = match description {
+            Some(description) => description_entries(&ruby, description)?,
+            None => Vec::new(),
+        };
+
+        Ok(Writer::with_options(WriterOptions {
+            database_type,
+            ip_version,
+            record_size,
+            languages: languages.unwrap_or_default(),
+            description,
+            alias_ipv6_to_ipv4: alias_ipv6_to_ipv4.unwrap_or(true),
+        }))
+    }
+
+    /// A Writer with the settings of the database described by `metadata`, apart
+    /// from its description and whether it aliases IPv6 ranges to IPv4
+    pub(crate) fn from_metadata(
+        metadata: &maxminddb::Metadata,
+        description: Vec<(String, String)>,
+        alias_ipv6_to_ipv4: bool,
+    ) -> Self {
+        Writer::with_options(WriterOptions {
+            database_type: metadata.database_type.clone(),
+            ip_version: metadata.ip_version,
+            record_size: metadata.record_size,
+            languages: metadata.languages.clone(),
+            description,
+            alias_ipv6_to_ipv4,
+        })
+    }
+
+    fn with_options(options: WriterOptions) -> Self {
+        Writer {
+            state: Mutex::new(WriterState::new(options.aliases())),
+            options,
+        }
+    }
+
+    /// Address bits and prefix length of `network` in the search tree, or None for an
+    /// IPv6 network in an IPv4 database or within an IPv4 alias. IPv4 networks in IPv6
+    /// databases are stored at `::a.b.c.d`, where readers look for them.
+    fn position(&self, network: IpNetwork) -> Option<(u128, u8, u8)> {
+        let position = match (network, self.options.ip_version) {
+            (IpNetwork::V4(network), 4) => {
+                Some((u32::from(network.network()) as u128, network.prefix(), 32))
+            }
+            (IpNetwork::V4(network), _) => Some((
+                u32::from(network.network()) as u128,
+                network.prefix() + 96,
+                128,
+            )),
+            (IpNetwork::V6(network), 6) => {
+                Some((u128::from(network.network()), network.prefix(), 128))
+            }
+            (IpNetwork::V6(_), _) => None,
+        };
+        position.filter(|&(address, prefix, _)| {
+            !(self.options.aliases() && in_ipv4_alias(address, prefix))
+        })
+    }
+
+    /// Parse `network` and find its position in the search tree
+    fn tree_position(&self, ruby: &magnus::Ruby, network: Value) -> Result<(u128, u8, u8), Error> {
+        let network = parse_network(ruby, network)?;
+        self.position(network).ok_or_else(|| {
+            let message = if network.is_ipv6() && self.options.ip_version == 4 {
+                format!(
+                    "Cannot insert IPv6 network '{}' into an IPv4-only database",
+                    network
+                )
+            } else {
+                format!(
+                    "Cannot insert network '{}' into an IPv4 alias; use the IPv4 network instead",
+                    network
+                )
+            };
+            Error::new(ruby.exception_arg_error(), message)
+        })
+    }
+
+    /// Store `value` for `network`, replacing any data already stored there. Returns
+    /// false, storing nothing, for an IPv6 network in an IPv4 database or within an
+    /// IPv4 alias.
+    pub(crate) fn insert_value(&self, network: IpNetwork, value: WriterValue) -> bool {
+        let Some((address, prefix, depth)) = self.position(network) else {
+            return false;
+        };
+        self.lock()
+            .insert(address, prefix, depth, value, MergeStrategy::Replace);
+        true
+    }
+
+    /// Store `data` for every address in `network`. `merge:` decides how it combines
+    /// with data already stored for any part of the network: `:replace` (default),
+    /// `:shallow` or `:deep` Hash merging, or `:keep` to only fill addresses without data.
+    fn insert(&self, args: &[Value]) -> Result<(), Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let args = scan_args::<(Value, Value), (), (), (), _, ()>(args)?;
+        let (network, data) = args.required;
+        let kw = get_kwargs::<_, (), (Option<Symbol>,), ()>(args.keywords, &[], &["merge"])?;
+        let (merge,) = kw.optional;
+        let merge = MergeStrategy::from_symbol(&ruby, merge)?;
+
+        let (address, prefix, depth) = self.tree_position(&ruby, network)?;
+        let value = WriterValue::from_ruby(&ruby, data)?;
+        self.lock().insert(address, prefix, depth, value, merge);
+        Ok(())
+    }
+
+    /// Remove the data for every address in `network`
+    fn remove(&self, network: Value) -> Result<(), Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        let (address, prefix, depth) = self.tree_position(&ruby, network)?;
+        self.lock().remove(address, prefix, depth);
+        Ok(())
+    }
+
+    /// Write the database to `path`, returning a Hash of sizes. The file is written
+    /// next to `path` and renamed into place, so readers watching it never see a
+    /// partial database.
+    pub(crate) fn write(&self, path: String) -> Result<RHash, Error> {
+        let ruby = magnus::Ruby::get().expect("Ruby VM should be available in Ruby method");
+
+        // The lock is taken and released without the GVL. Holding it while waiting to
+        // get the GVL back could deadlock with a thread blocked in `insert`.
+        let stats = without_gvl(|| {
+            let (bytes, stats) = self.lock().build(&self.options)?;
+            let (temp_path, mut file) = create_temp_file(&path)
+                .map_err(|e| WriteError::Io(format!("Failed to write {}: {}", path, e)))?;
+            let written = file.write_all(&bytes);
+            drop(file);
+            written
+                .and_then(|()| fs::rename(&temp_path, &path))
+                .map_err(|e| {
+                    let _ = fs::remove_file(&temp_path);
+                    WriteError::Io(format!("Failed to write {}: {}", path, e))
+                })?;
+            Ok::<_, WriteError>(stats)
+        })
+        .map_err(|e| match e {
+            WriteError::TooLarge(message) => Error::new(ruby.exception_runtime_error(), message),
+            WriteError::Io(message) => Error::new(ruby.exception_io_error(), message),
+        })?;
+
+        let hash = ruby.hash_new_capa(5);
+        hash.aset(ruby.to_symbol("node_count"), stats.node_count)?;
+        hash.aset(ruby.to_symbol("data_section_size"), stats.data_section_size)?;
+        hash.aset(
+            ruby.to_symbol("undeduplicated_size"),
+            stats.undeduplicated_size,
+        )?;
+        hash.aset(
+            ruby.to_symbol("dedup_ratio"),
+            if stats.data_section_size == 0 {
+                1.0
+            } else {
+                stats.undeduplicated_size as f64 / stats.data_section_size as f64
+            },
+        )?;
+        hash.aset(ruby.to_symbol("file_size"), stats.file_size)?;
+        Ok(hash)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WriterState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Read a `description:` Hash of language codes to descriptions
+pub(crate) fn description_entries(
+    ruby: &magnus::Ruby,
+    description: RHash,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut entries = Vec::new();
+    description.foreach(|language: Value, text: String| {
+        entries.push((map_key(ruby, language)?, text));
+        Ok(ForEach::Continue)
+    })?;
+    Ok(entries)
+}
+
+pub(crate) fn init(ruby: &magnus::Ruby, rust: RModule) -> Result<(), Error> {
+    let writer_class = rust.define_class("Writer", ruby.class_object())?;
+    writer_class.define_singleton_method("new", magnus::function!(Writer::new, -1))?;
+    writer_class.define_method("insert", magnus::method!(Writer::insert, -1))?;
+    writer_class.define_method("remove", magnus::method!(Writer::remove, 1))?;
+    writer_class.define_method("write", magnus::method!(Writer::write, 1))?;
+    Ok(())
+}
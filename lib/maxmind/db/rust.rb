@@ -64,6 +64,8 @@ module MaxMind
       #
       # The Rust module (MaxMind::DB::Rust) defines:
       # - Reader class
+      # - Writer class
+      # - OverlayReader class
       # - Metadata class
       # - InvalidDatabaseError exception
       # - MODE_AUTO, MODE_MEMORY, MODE_MMAP constants
@@ -126,6 +128,8 @@ module MaxMind
       module Rust
         # The native extension defines:
         # - Reader class
+        # - Writer class
+        # - OverlayReader class
         # - Metadata class
         # - InvalidDatabaseError exception
         # - MODE_AUTO, MODE_MEMORY, MODE_MMAP constants
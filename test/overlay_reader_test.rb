@@ -0,0 +1,148 @@
+# frozen_string_literal: true
+
+require 'test_helper'
+require 'fileutils'
+require 'tmpdir'
+
+class OverlayReaderTest < Minitest::Test
+  VENDOR = { 'site' => 'hq', 'country' => { 'iso_code' => 'US', 'name' => 'United States' } }.freeze
+
+  def setup
+    @dir = Dir.mktmpdir
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    writer.insert('10.0.0.0/8', VENDOR)
+    writer.write(path('base.mmdb'))
+    @base = MaxMind::DB::Rust::Reader.new(path('base.mmdb'))
+  end
+
+  def teardown
+    @base.close
+    FileUtils.rm_rf(@dir)
+  end
+
+  def test_hash_overrides
+    overlay = MaxMind::DB::Rust::OverlayReader.new(@base, { '10.1.0.0/16' => { 'site' => 'lab' },
+                                                            IPAddr.new('192.168.0.0/24') => { 'site' => 'home' } })
+
+    assert_equal [{ 'site' => 'lab' }, 16], overlay.get_with_prefix_length('10.1.2.3')
+    assert_equal({ 'site' => 'home' }, overlay.get('192.168.0.1'))
+    # 10.2.0.0/15 is the largest network around the address without an override
+    assert_equal [VENDOR, 15], overlay.get_with_prefix_length('10.2.0.1')
+    assert_nil overlay.get('11.0.0.1')
+  end
+
+  def test_override_records_are_copied_and_frozen
+    overrides = { '10.1.0.0/16' => { 'site' => 'lab', 'tags' => ['a'] } }
+    overlay = MaxMind::DB::Rust::OverlayReader.new(@base, overrides)
+
+    record = overlay.get('10.1.0.1')
+    assert_predicate record, :frozen?
+    assert_raises(FrozenError) { record['site'] = 'changed' }
+    assert_raises(FrozenError) { record['tags'] << 'b' }
+
+    overrides['10.1.0.0/16']['site'] = 'changed'
+    overrides['10.1.0.0/16']['tags'] << 'b'
+    assert_equal({ 'site' => 'lab', 'tags' => ['a'] }, overlay.get('10.1.0.1'))
+
+    deep = MaxMind::DB::Rust::OverlayReader.new(@base, { '192.168.0.0/24' => { 'site' => 'home' } }, merge: :deep)
+    assert_predicate deep.get('192.168.0.1'), :frozen?
+  end
+
+  def test_records_are_always_frozen
+    overrides = { '10.1.0.0/16' => { 'country' => { 'iso_code' => 'CA' } } }
+    deep = MaxMind::DB::Rust::OverlayReader.new(@base, overrides, merge: :deep)
+    shallow = MaxMind::DB::Rust::OverlayReader.new(@base, overrides, merge: :shallow)
+
+    [deep.get('10.1.2.3'), shallow.get('10.1.2.3'), deep.get('10.2.0.1')].each do |record|
+      assert_predicate record, :frozen?
+      assert_predicate record['country'], :frozen?
+      assert_predicate record['country']['iso_code'], :frozen?
+    end
+    refute_predicate @base.get('10.2.0.1'), :frozen?
+  end
+
+  def test_ipv4_overrides_in_ipv6_database
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 6)
+    writer.insert('10.0.0.0/8', VENDOR)
+    writer.write(path('base6.mmdb'))
+    base = MaxMind::DB::Rust::Reader.new(path('base6.mmdb'))
+    overlay = MaxMind::DB::Rust::OverlayReader.new(base, { '10.1.0.0/16' => { 'site' => 'lab' } })
+
+    assert_equal [{ 'site' => 'lab' }, 16], overlay.get_with_prefix_length('10.1.2.3')
+    assert_equal [{ 'site' => 'lab' }, 112], overlay.get_with_prefix_length('::10.1.2.3')
+    assert_equal [{ 'site' => 'lab' }, 112], overlay.get_with_prefix_length('::ffff:10.1.2.3')
+    assert_equal [VENDOR, 111], overlay.get_with_prefix_length('::10.2.0.1')
+
+    mixed = MaxMind::DB::Rust::OverlayReader.new(base, { '::10.1.2.0/120' => { 'site' => 'rack' },
+                                                         '10.0.0.0/8' => { 'site' => 'corp' } })
+    assert_equal [{ 'site' => 'rack' }, 120], mixed.get_with_prefix_length('::10.1.2.3')
+    assert_equal [{ 'site' => 'corp' }, 120], mixed.get_with_prefix_length('::10.1.3.1')
+    assert_equal({ 'site' => 'corp' }, mixed.get('::ffff:10.1.2.3'))
+
+    everything = MaxMind::DB::Rust::OverlayReader.new(base, { '0.0.0.0/0' => { 'site' => 'any' } })
+    assert_equal({ 'site' => 'any' }, everything.get('::5.5.5.5'))
+    assert_nil everything.get('::1')
+    assert_nil everything.get('::')
+
+    base.close
+  end
+
+  def test_longest_prefix_wins
+    overlay = MaxMind::DB::Rust::OverlayReader.new(@base, { '10.0.0.0/8' => { 'site' => 'outer' },
+                                                            '10.1.0.0/16' => { 'site' => 'inner' } })
+
+    assert_equal [{ 'site' => 'inner' }, 16], overlay.get_with_prefix_length('10.1.0.1')
+    assert_equal [{ 'site' => 'outer' }, 15], overlay.get_with_prefix_length('10.2.0.1')
+  end
+
+  def test_merge
+    overrides = { '10.1.0.0/16' => { 'country' => { 'iso_code' => 'CA' }, 'team' => 'infra' },
+                  '192.168.0.0/24' => { 'site' => 'home' } }
+    deep = MaxMind::DB::Rust::OverlayReader.new(@base, overrides, merge: :deep)
+    shallow = MaxMind::DB::Rust::OverlayReader.new(@base, overrides, merge: :shallow)
+
+    assert_equal [{ 'site' => 'hq', 'country' => { 'iso_code' => 'CA', 'name' => 'United States' }, 'team' => 'infra' }, 16],
+                 deep.get_with_prefix_length('10.1.2.3')
+    assert_equal({ 'site' => 'hq', 'country' => { 'iso_code' => 'CA' }, 'team' => 'infra' }, shallow.get('10.1.2.3'))
+    assert_equal({ 'site' => 'home' }, deep.get('192.168.0.1'))
+    assert_equal VENDOR, deep.get('10.2.0.1')
+    assert_equal VENDOR, @base.get('10.1.2.3')
+  end
+
+  def test_get_many_and_metadata
+    overlay = MaxMind::DB::Rust::OverlayReader.new(@base, { '10.1.0.0/16' => { 'site' => 'lab' } })
+
+    assert_equal [{ 'site' => 'lab' }, VENDOR, nil], overlay.get_many(['10.1.0.1', '10.2.0.1', '11.0.0.1'])
+    assert_raises(ArgumentError) { overlay.get_many(['invalid']) }
+    assert_equal @base.metadata.database_type, overlay.metadata.database_type
+  end
+
+  def test_reader_overrides
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    writer.insert('10.0.0.0/24', { 'site' => 'lab' })
+    writer.write(path('overrides.mmdb'))
+    overrides = MaxMind::DB::Rust::Reader.new(path('overrides.mmdb'))
+
+    overlay = MaxMind::DB::Rust::OverlayReader.new(@base, overrides)
+    assert_equal [{ 'site' => 'lab' }, 24], overlay.get_with_prefix_length('10.0.0.1')
+    # The override database has no data in 10.128.0.0/9
+    assert_equal [VENDOR, 9], overlay.get_with_prefix_length('10.128.0.1')
+
+    overrides.close
+  end
+
+  def test_invalid_arguments
+    assert_raises(TypeError) { MaxMind::DB::Rust::OverlayReader.new({}, {}) }
+    assert_raises(TypeError) { MaxMind::DB::Rust::OverlayReader.new(@base, 'overrides') }
+    assert_raises(ArgumentError) { MaxMind::DB::Rust::OverlayReader.new(@base, {}, merge: :keep) }
+    assert_raises(ArgumentError) { MaxMind::DB::Rust::OverlayReader.new(@base, { '2001:db8::/32' => {} }) }
+    assert_raises(ArgumentError) { MaxMind::DB::Rust::OverlayReader.new(@base, { 'not a network' => {} }) }
+    assert_raises(ArgumentError) { MaxMind::DB::Rust::OverlayReader.new(@base, {}).get('2001:db8::1') }
+  end
+
+  private
+
+  def path(name)
+    File.join(@dir, name)
+  end
+end
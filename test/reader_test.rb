@@ -1,6 +1,12 @@
 # frozen_string_literal: true
 
 require 'test_helper'
+require 'fileutils'
+require 'json'
+require 'stringio'
+require 'rubygems/package'
+require 'tmpdir'
+require 'zlib'
 
 class ReaderTest < Minitest::Test
   TEST_DATA_DIR = File.join(__dir__, 'data', 'MaxMind-DB', 'test-data')
@@ -122,6 +128,200 @@ class ReaderTest < Minitest::Test
     reader.close
   end
 
+  def test_get_with_network
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+
+    record, network = reader.get_with_network('1.1.1.3')
+    assert_equal({ 'ip' => '1.1.1.2' }, record)
+    assert_instance_of IPAddr, network
+    assert_equal '1.1.1.2', network.to_s
+    assert_equal 31, network.prefix
+
+    record, network = reader.get_with_network(IPAddr.new('1.1.1.1'))
+    assert_equal({ 'ip' => '1.1.1.1' }, record)
+    assert_equal 32, network.prefix
+
+    record, network = reader.get_with_network('2.2.2.2')
+    assert_nil record
+    assert_includes network, IPAddr.new('2.2.2.2')
+
+    reader.close
+  end
+
+  def test_get_with_network_ipv4_in_ipv6_database
+    path = File.join(TEST_DATA_DIR, 'MaxMind-DB-test-mixed-24.mmdb')
+    skip 'Test database not found' unless File.exist?(path)
+
+    reader = MaxMind::DB::Rust::Reader.new(path)
+    record, network = reader.get_with_network('1.1.1.3')
+
+    assert_equal({ 'ip' => '::1.1.1.2' }, record)
+    assert_predicate network, :ipv4?
+    assert_equal '1.1.1.2', network.to_s
+    assert_equal 31, network.prefix
+    assert_equal [record, network.prefix], reader.get_with_prefix_length('1.1.1.3')
+
+    reader.close
+  end
+
+  def test_lookup
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    result = reader.lookup('1.1.1.3')
+
+    assert_instance_of MaxMind::DB::Rust::LookupResult, result
+    assert_predicate result, :found?
+    assert_equal 31, result.prefix_length
+    assert_equal '1.1.1.2', result.network.to_s
+    assert_kind_of Integer, result.data_offset
+    assert_equal({ 'ip' => '1.1.1.2' }, result.data)
+    assert_same result.data, result.data
+
+    # Addresses sharing a record share its offset
+    assert_equal result.data_offset, reader.lookup('1.1.1.2').data_offset
+    refute_equal result.data_offset, reader.lookup('1.1.1.1').data_offset
+
+    reader.close
+    assert_equal({ 'ip' => '1.1.1.2' }, result.data)
+  end
+
+  def test_lookup_across_reload
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'reload.mmdb')
+      FileUtils.cp(ipv4_test_db_path, path)
+      reader = MaxMind::DB::Rust::Reader.new(path)
+      result = reader.lookup('1.1.1.3')
+
+      writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+      writer.insert('1.1.1.0/24', { 'ip' => 'replaced' })
+      writer.write(path)
+      reader.reload!
+
+      assert_equal({ 'ip' => 'replaced' }, reader.lookup('1.1.1.3').data)
+      assert_equal 31, result.prefix_length
+      assert_equal({ 'ip' => '1.1.1.2' }, result.data)
+
+      reader.close
+    end
+  end
+
+  def test_lookup_not_found
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    result = reader.lookup('2.2.2.2')
+
+    refute_predicate result, :found?
+    assert_nil result.data_offset
+    assert_nil result.data
+    assert_includes result.network, IPAddr.new('2.2.2.2')
+
+    reader.close
+  end
+
+  def test_get_json
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    json = reader.get_json('81.2.69.160')
+
+    assert_equal Encoding::UTF_8, json.encoding
+    assert_equal reader.get('81.2.69.160'), JSON.parse(json)
+    assert_nil reader.get_json('127.0.0.1')
+
+    record, network = reader.get_with_network('81.2.69.160')
+    assert_equal({ 'network' => "#{network}/#{network.prefix}", 'data' => record },
+                 JSON.parse(reader.get_json('81.2.69.160', network: true)))
+    assert_nil JSON.parse(reader.get_json('127.0.0.1', network: true))['data']
+
+    reader.close
+  end
+
+  def test_get_many_json
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    results = reader.get_many_json(['1.1.1.1', '2.2.2.2', 'invalid'], on_error: :nil)
+
+    assert_equal ['{"ip":"1.1.1.1"}', nil, nil], results
+    assert_equal ['{"network":"1.1.1.2/31","data":{"ip":"1.1.1.2"}}'],
+                 reader.get_many_json(['1.1.1.3'], network: true)
+    assert_raises(ArgumentError) { reader.get_many_json(['invalid']) }
+
+    reader.close
+  end
+
+  def test_get_lazy
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    record = reader.get_lazy('81.2.69.160')
+    expected = reader.get('81.2.69.160')
+
+    assert_instance_of MaxMind::DB::Rust::Record, record
+    assert_equal expected['country'], record['country']
+    assert_equal expected['country'], record[:country]
+    assert_equal 'London', record.dig('city', 'names', 'en')
+    assert_equal 'ENG', record.dig('subdivisions', 0, 'iso_code')
+    assert_nil record['missing']
+    assert_equal expected, record.to_h
+    assert_equal expected.keys, record.map { |key, _value| key }
+    assert_equal expected.to_a, record.each.to_a
+    assert_equal reader.lookup('81.2.69.160').data_offset, record.data_offset
+
+    reader.close
+    assert_equal 'GB', record.dig('country', 'iso_code')
+  end
+
+  def test_get_lazy_fetch
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    record = reader.get_lazy('81.2.69.160')
+
+    assert_equal 'GB', record.fetch('country')['iso_code']
+    assert_equal :default, record.fetch('missing', :default)
+    assert_equal 'missing!', record.fetch('missing') { |key| "#{key}!" }
+    assert_raises(KeyError) { record.fetch('missing') }
+
+    reader.close
+  end
+
+  def test_get_lazy_across_reload
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'reload.mmdb')
+      FileUtils.cp(ipv4_test_db_path, path)
+      reader = MaxMind::DB::Rust::Reader.new(path)
+      record = reader.get_lazy('1.1.1.1')
+
+      writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+      writer.insert('1.1.1.0/24', { 'ip' => 'replaced' })
+      writer.write(path)
+      reader.reload!
+
+      assert_equal({ 'ip' => 'replaced' }, reader.get('1.1.1.1'))
+      assert_equal '1.1.1.1', record['ip']
+      assert_equal({ 'ip' => '1.1.1.1' }, record.to_h)
+
+      reader.close
+    end
+  end
+
+  def test_get_lazy_not_found
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    assert_nil reader.get_lazy('2.2.2.2')
+    reader.close
+  end
+
   def test_get_returns_hash
     skip 'Test database not found' unless File.exist?(test_db_path)
 
@@ -235,6 +435,78 @@ class ReaderTest < Minitest::Test
     reader.close
   end
 
+  def test_each_without_block_returns_enumerator
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    enum = reader.each
+
+    assert_kind_of Enumerator, enum
+    assert_nil enum.size
+
+    network, data = enum.next
+    assert_kind_of IPAddr, network
+    assert_kind_of Hash, data
+    enum.rewind
+    assert_equal [network, data], enum.next
+
+    gb = reader.each.lazy.select { |_network, record| record.dig('country', 'iso_code') == 'GB' }.first(2)
+    assert_equal 2, gb.size
+    gb.each { |_network, record| assert_equal 'GB', record['country']['iso_code'] }
+
+    assert_equal 3, reader.each.each_slice(3).first.size
+
+    reader.close
+  end
+
+  def test_each_enumerator_stops_early
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    total = reader.each.count
+    assert_operator total, :>, 3
+
+    # Each record the walk decodes is yielded straight to the consumer, so the
+    # number of yields is how far the tree was walked
+    yielded = 0
+    first = reader.each.lazy.map do |network, _data|
+      yielded += 1
+      network.to_s
+    end.first(2)
+    assert_equal %w[1.1.1.1 1.1.1.2], first
+    assert_equal 2, yielded
+
+    yielded = 0
+    reader.each.each_with_index do |_, index|
+      yielded += 1
+      break if index == 2
+    end
+    assert_equal 3, yielded
+
+    enumerator = reader.each
+    assert_equal '1.1.1.1', enumerator.next.first.to_s
+    assert_equal '1.1.1.2', enumerator.next.first.to_s
+    reader.close
+  end
+
+  def test_each_enumerator_within_network
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+
+    yielded = []
+    reader.each('1.1.1.0/24') { |network, data| yielded << [network, data] }
+    assert_equal yielded, reader.each('1.1.1.0/24').to_a
+    assert_equal(%w[1.1.1.1 1.1.1.2], reader.each('1.1.1.0/24').first(2).map { |network, _data| network.to_s })
+
+    with_empty = []
+    reader.each('1.1.1.0/24', include_empty: true) { |network, data| with_empty << [network, data] }
+    assert_equal with_empty, reader.each('1.1.1.0/24', include_empty: true).to_a
+    assert_operator with_empty.size, :>, yielded.size
+
+    reader.close
+  end
+
   def test_iterator_within_ipv4_network_string
     skip 'Test database not found' unless File.exist?(test_db_path)
 
@@ -351,6 +623,352 @@ class ReaderTest < Minitest::Test
     reader.close
   end
 
+  def test_each_include_empty
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    with_data = reader.each('1.1.1.0/24').to_a
+    all = reader.each('1.1.1.0/24', include_empty: true).to_a
+
+    assert_operator all.size, :>, with_data.size
+    empty = all.select { |_network, data| data.nil? }
+    refute_empty empty
+    assert_equal with_data, all - empty
+    refute(empty.any? { |network, _data| network.include?(IPAddr.new('1.1.1.1')) })
+
+    reader.close
+  end
+
+  def test_each_include_aliased
+    path = File.join(TEST_DATA_DIR, 'MaxMind-DB-test-mixed-24.mmdb')
+    skip 'Test database not found' unless File.exist?(path)
+
+    reader = MaxMind::DB::Rust::Reader.new(path)
+    ipv4_mapped = IPAddr.new('::ffff:0:0/96')
+
+    plain = reader.each.to_a
+    aliased = reader.each(include_aliased: true).to_a
+
+    refute(plain.any? { |network, _data| ipv4_mapped.include?(network) })
+    assert(aliased.any? { |network, _data| ipv4_mapped.include?(network) })
+    assert_empty plain - aliased
+
+    reader.close
+  end
+
+  def test_each_skip_empty_values
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    records = reader.each(skip_empty_values: true).map { |_network, data| data }
+
+    refute_empty records
+    assert(records.none? { |data| data.respond_to?(:empty?) && data.empty? })
+    assert_raises(ArgumentError) { reader.each(unknown_option: true) }
+
+    reader.close
+  end
+
+  def test_cursor
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    expected = reader.each.map { |network, data| ["#{network}/#{network.prefix}", data] }
+    cursor = reader.cursor
+
+    assert_nil cursor.position
+    first = cursor.next
+    assert_equal expected.first, ["#{first[0]}/#{first[0].prefix}", first[1]]
+    assert_equal expected[0][0], cursor.position
+
+    batch = cursor.next_batch(3)
+    assert_equal expected[1, 3], batch.map { |network, data| ["#{network}/#{network.prefix}", data] }
+    assert_equal expected[3][0], cursor.position
+
+    rest = []
+    while (pair = cursor.next)
+      rest << pair
+    end
+    assert_equal expected.size - 4, rest.size
+    assert_nil cursor.next
+    assert_empty cursor.next_batch(10)
+
+    reader.close
+  end
+
+  def test_cursor_start_after
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    expected = reader.each.map { |network, data| ["#{network}/#{network.prefix}", data] }
+    middle = expected.size / 2
+
+    cursor = reader.cursor
+    cursor.next_batch(middle + 1)
+    resumed = reader.cursor(start_after: cursor.position)
+    rest = resumed.next_batch(expected.size).map { |network, data| ["#{network}/#{network.prefix}", data] }
+
+    assert_equal expected[(middle + 1)..], rest
+    assert_empty reader.cursor(start_after: expected.last[0]).next_batch(10)
+    assert_raises(ArgumentError) { reader.cursor(start_after: 'not a network') }
+
+    reader.close
+  end
+
+  def test_cursor_within_network
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    cursor = reader.cursor('1.1.1.0/24', start_after: '1.1.1.1/32')
+    network, data = cursor.next
+
+    assert_equal '1.1.1.2', network.to_s
+    assert_equal({ 'ip' => '1.1.1.2' }, data)
+
+    reader.close
+    cursor.next_batch(10)
+    error = assert_raises(RuntimeError) { reader.cursor }
+    assert_match(/closed/, error.message)
+  end
+
+  def test_each_partition
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    expected = reader.each.map { |network, data| ["#{network}/#{network.prefix}", data] }
+    partitions = reader.each_partition(4)
+
+    assert_equal 4, partitions.size
+    results = partitions.map do |cursor|
+      Thread.new { cursor.map { |network, data| ["#{network}/#{network.prefix}", data] } }
+    end.map(&:value)
+
+    assert_equal expected, results.flatten(1)
+    assert_operator results.count(&:any?), :>, 1
+
+    assert_raises(ArgumentError) { reader.each_partition(0) }
+    assert_raises(ArgumentError) { reader.each_partition(-1) }
+    assert_equal 4, reader.each_partition(2**40, '1.1.1.0/30').size
+    assert_operator reader.each_partition(2**40).size, :<=, reader.metadata.node_count * 2
+    reader.close
+  end
+
+  def test_each_partition_after_close
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    expected = reader.each('1.1.1.0/24').to_a
+    partitions = reader.each_partition(2, '1.1.1.0/24')
+    reader.close
+
+    assert_equal expected, partitions.flat_map(&:to_a)
+  end
+
+  def test_export_jsonl
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    expected = reader.each.map { |network, data| [network, network.prefix, data] }
+    io = StringIO.new(String.new)
+
+    assert_equal expected.size, reader.export(io)
+    exported = io.string.each_line.map do |line|
+      row = JSON.parse(line)
+      network = IPAddr.new(row['network'])
+      [network, network.prefix, row['data']]
+    end
+    assert_equal expected, exported
+
+    reader.close
+  end
+
+  def test_export_csv_to_path
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'export.csv')
+      count = reader.export(path, format: :csv, network: '81.2.69.0/24',
+                                  columns: %w[country.iso_code subdivisions.0.iso_code city.names location])
+      lines = File.readlines(path, chomp: true)
+
+      assert_equal count + 1, lines.size
+      assert_equal 'network,country.iso_code,subdivisions.0.iso_code,city.names,location', lines.first
+      lines.drop(1).each { |line| assert_kind_of IPAddr, IPAddr.new(line.split(',').first) }
+      assert(lines.any? { |line| line.match?(/,GB,ENG,"\{.*""en"":""London"".*\}",/) })
+    end
+
+    reader.close
+  end
+
+  def test_export_jsonl_columns
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    io = StringIO.new(String.new)
+    reader.export(io, network: '81.2.69.160/27', columns: %w[country.iso_code location.time_zone missing.key])
+    rows = io.string.each_line.map { |line| JSON.parse(line) }
+
+    refute_empty rows
+    london = rows.find { |row| row['location.time_zone'] == 'Europe/London' }
+    assert_equal({ 'network' => london['network'], 'country.iso_code' => 'GB',
+                   'location.time_zone' => 'Europe/London', 'missing.key' => nil }, london)
+
+    reader.close
+  end
+
+  def test_export_errors
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    io = StringIO.new(String.new)
+
+    assert_raises(ArgumentError) { reader.export(io, format: :xml) }
+    assert_raises(ArgumentError) { reader.export(io, format: :csv) }
+
+    broken = Object.new
+    def broken.write(_data) = raise(IOError, 'disk full')
+    error = assert_raises(IOError) { reader.export(broken) }
+    assert_equal 'disk full', error.message
+
+    reader.close
+  end
+
+  def test_export_rejects_invalid_utf8
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'bad.mmdb')
+      writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+      writer.insert('1.2.3.0/24', { 'name' => "\xFF\xFE".b })
+      writer.write(path)
+      # Retype the two-byte bytes value as a UTF-8 string, checking that the bytes
+      # appear exactly once so the substitution hits that value and nothing else
+      data = File.binread(path)
+      bytes_value = "\x82\xFF\xFE".b
+      assert_equal 1, data.scan(bytes_value).size
+      File.binwrite(path, data.sub(bytes_value, "\x42\xFF\xFE".b))
+
+      reader = MaxMind::DB::Rust::Reader.new(path)
+      assert_raises(MaxMind::DB::Rust::InvalidDatabaseError) { reader.export(StringIO.new(String.new)) }
+      assert_raises(MaxMind::DB::Rust::InvalidDatabaseError) do
+        reader.export(StringIO.new(String.new), columns: %w[name])
+      end
+      assert_raises(MaxMind::DB::Rust::InvalidDatabaseError) { reader.get_json('1.2.3.4') }
+      reader.close
+    end
+  end
+
+  def test_write_subset
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'subset.mmdb')
+      stats = reader.write_subset(path, network: '81.2.69.0/24', fields: %w[country location])
+      subset = MaxMind::DB::Rust::Reader.new(path)
+
+      reader.each('81.2.69.0/24') do |network, data|
+        assert_equal data.slice('country', 'location'), subset.get(network.to_s), network.to_s
+      end
+      assert_nil subset.get('2.125.160.216')
+      assert_equal File.size(path), stats[:file_size]
+
+      metadata = subset.metadata
+      assert_equal reader.metadata.database_type, metadata.database_type
+      assert_equal reader.metadata.languages, metadata.languages
+      assert_equal reader.metadata.record_size, metadata.record_size
+      assert_equal reader.metadata.description.transform_values { |text| "#{text} (subset)" }, metadata.description
+      assert_in_delta Time.now.to_i, metadata.build_epoch, 60
+
+      subset.close
+    end
+
+    reader.close
+  end
+
+  def test_write_subset_preserves_records
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'copy.mmdb')
+      reader.write_subset(path, description: { 'en' => 'Copy' })
+      copy = MaxMind::DB::Rust::Reader.new(path)
+
+      reader.each { |network, data| assert_equal data, copy.get(network.to_s), network.to_s }
+      assert_equal({ 'en' => 'Copy' }, copy.metadata.description)
+
+      copy.close
+    end
+
+    reader.close
+  end
+
+  def test_write_subset_keeps_ipv4_aliasing
+    Dir.mktmpdir do |dir|
+      [true, false].each do |aliases|
+        source_path = File.join(dir, "source-#{aliases}.mmdb")
+        writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', alias_ipv6_to_ipv4: aliases)
+        writer.insert('1.2.3.0/24', { 'name' => 'ipv4' })
+        unless aliases
+          writer.insert('::ffff:5.6.7.0/120', { 'name' => 'mapped' })
+          writer.insert('2002:909::/32', { 'name' => '6to4' })
+        end
+        writer.write(source_path)
+
+        path = File.join(dir, "subset-#{aliases}.mmdb")
+        reader = MaxMind::DB::Rust::Reader.new(source_path)
+        reader.write_subset(path)
+        reader.close
+        subset = MaxMind::DB::Rust::Reader.new(path)
+
+        assert_equal({ 'name' => 'ipv4' }, subset.get('1.2.3.4'))
+        if aliases
+          assert_equal({ 'name' => 'ipv4' }, subset.get('::ffff:1.2.3.4'))
+          assert_equal({ 'name' => 'ipv4' }, subset.get('2002:102:304::'))
+        else
+          assert_nil subset.get('::ffff:1.2.3.4')
+          assert_equal({ 'name' => 'mapped' }, subset.get('::ffff:5.6.7.8'))
+          assert_equal({ 'name' => '6to4' }, subset.get('2002:909::1'))
+        end
+        subset.close
+      end
+    end
+  end
+
+  def test_write_subset_where
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'gb.mmdb')
+      seen = []
+      reader.write_subset(path, fields: %w[city], where: lambda { |network, data|
+        seen << network
+        assert(data.keys.all?(&:frozen?))
+        data.dig('country', 'iso_code') == 'GB'
+      })
+      subset = MaxMind::DB::Rust::Reader.new(path)
+
+      assert_equal reader.each.count, seen.size
+      assert(seen.all? { |network| network.is_a?(IPAddr) })
+      reader.each do |network, data|
+        if data.dig('country', 'iso_code') == 'GB'
+          assert_equal data.slice('city'), subset.get(network.to_s), network.to_s
+        else
+          assert_nil subset.get(network.to_s), network.to_s
+        end
+      end
+
+      subset.close
+
+      assert_raises(TypeError) { reader.write_subset(path, where: 'GB') }
+      error = assert_raises(RuntimeError) { reader.write_subset(path, where: ->(_network, _data) { raise 'stop' }) }
+      assert_equal 'stop', error.message
+    end
+
+    reader.close
+  end
+
   def test_iterator_within_modes
     skip 'Test database not found' unless File.exist?(test_db_path)
 
@@ -398,7 +1016,663 @@ class ReaderTest < Minitest::Test
     reader.close
   end
 
-  private
+  def test_get_many
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    records = reader.get_many(['1.1.1.1', IPAddr.new('1.1.1.3'), '2.2.2.2'])
+
+    assert_equal [{ 'ip' => '1.1.1.1' }, { 'ip' => '1.1.1.2' }, nil], records
+    assert_equal [], reader.get_many([])
+
+    reader.close
+  end
+
+  def test_get_many_with_prefix_length
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    results = reader.get_many_with_prefix_length(['1.1.1.1', '1.1.1.3'])
+
+    assert_equal [[{ 'ip' => '1.1.1.1' }, 32], [{ 'ip' => '1.1.1.2' }, 31]], results
+
+    reader.close
+  end
+
+  def test_get_many_error_handling
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    ips = ['1.1.1.1', 'not_ip', '2001::']
+
+    assert_raises(ArgumentError) { reader.get_many(ips) }
+    assert_raises(ArgumentError) { reader.get_many(ips, on_error: :raise) }
+
+    assert_equal [{ 'ip' => '1.1.1.1' }, nil, nil], reader.get_many(ips, on_error: :nil)
+
+    results = reader.get_many(ips, on_error: :exception)
+
+    assert_equal({ 'ip' => '1.1.1.1' }, results[0])
+    assert_kind_of ArgumentError, results[1]
+    assert_includes results[1].message, 'does not appear to be'
+    assert_kind_of ArgumentError, results[2]
+    assert_includes results[2].message, 'IPv4-only database'
+
+    assert_raises(ArgumentError) { reader.get_many(ips, on_error: :bogus) }
+
+    reader.close
+  end
+
+  def test_get_many_after_close
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    reader.close
+
+    error = assert_raises(RuntimeError) do
+      reader.get_many(['1.1.1.1'], on_error: :nil)
+    end
+    assert_match(/closed/, error.message)
+  end
+
+  def test_release_gvl_option
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    [MaxMind::DB::Rust::MODE_MMAP, MaxMind::DB::Rust::MODE_MEMORY].each do |mode|
+      reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, mode: mode, release_gvl: true)
+
+      assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+      assert_equal [{ 'ip' => '1.1.1.2' }, 31], reader.get_with_prefix_length('1.1.1.3')
+      assert_nil reader.get('2.2.2.2')
+
+      reader.close
+    end
+  end
+
+  def test_release_gvl_concurrent_lookups
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, release_gvl: true)
+    threads = Array.new(4) do
+      Thread.new do
+        Array.new(500) { reader.get('1.1.1.3') }
+      end
+    end
+
+    threads.each do |thread|
+      assert(thread.value.all?({ 'ip' => '1.1.1.2' }))
+    end
+
+    reader.close
+  end
+
+  def test_dig
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+
+    assert_equal 'GB', reader.dig('81.2.69.160', 'country', 'iso_code')
+    assert_equal 'GB', reader.dig('81.2.69.160', :country, :iso_code)
+    assert_equal 'ENG', reader.dig('81.2.69.160', 'subdivisions', 0, 'iso_code')
+    assert_equal 'ENG', reader.dig('81.2.69.160', 'subdivisions', -1, 'iso_code')
+    assert_equal reader.get('81.2.69.160')['country'], reader.dig('81.2.69.160', 'country')
+    assert_equal reader.get('81.2.69.160'), reader.dig('81.2.69.160')
+
+    reader.close
+  end
+
+  def test_dig_missing_path
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+
+    assert_nil reader.dig('81.2.69.160', 'country', 'no_such_key')
+    assert_nil reader.dig('81.2.69.160', 'no_such_key', 'iso_code')
+    assert_nil reader.dig('81.2.69.160', 'subdivisions', 10, 'iso_code')
+    assert_nil reader.dig('10.0.0.1', 'country', 'iso_code')
+
+    assert_raises(ArgumentError) { reader.dig('81.2.69.160', 'country', 1.5) }
+
+    reader.close
+  end
+
+  def test_get_with_path
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+
+    assert_equal 'GB', reader.get('81.2.69.160', %w[country iso_code])
+    assert_nil reader.get('81.2.69.160', %w[country no_such_key])
+    assert_equal reader.get('81.2.69.160'), reader.get('81.2.69.160', nil)
+    assert_raises(ArgumentError) { reader.get }
+    assert_raises(ArgumentError) { reader.get('81.2.69.160', %w[country], 1) }
+
+    reader.close
+  end
+
+  def test_fields_option
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    full_reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    full = full_reader.get('81.2.69.160')
+    full_reader.close
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path, fields: ['country', :location])
+    record = reader.get('81.2.69.160')
+
+    assert_equal %w[country location], record.keys.sort
+    assert_equal full['country'], record['country']
+    assert_equal full['location'], record['location']
+
+    _record, prefix_length = reader.get_with_prefix_length('81.2.69.160')
+
+    assert_operator prefix_length, :>, 0
+    assert_equal [record], reader.get_many(['81.2.69.160'])
+
+    # Projection only applies to the top level of the record
+    assert_equal full['city']['names'], reader.dig('81.2.69.160', 'city', 'names')
+
+    reader.each('81.2.69.160/32') do |_network, data|
+      assert_equal %w[country location], data.keys.sort
+    end
+
+    reader.close
+  end
+
+  def test_get_only_option
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+
+    record = reader.get('81.2.69.160', only: %w[city])
+
+    assert_equal %w[city], record.keys
+    assert_equal 'London', record['city']['names']['en']
+
+    assert_equal({}, reader.get('81.2.69.160', only: %w[no_such_key]))
+    assert_nil reader.get('10.0.0.1', only: %w[city])
+    assert_equal 'GB', reader.get('81.2.69.160', %w[country iso_code], only: %w[city])
+    assert_raises(ArgumentError) { reader.get('81.2.69.160', only: [1]) }
+    assert_raises(ArgumentError) { reader.get('81.2.69.160', bogus: true) }
+
+    reader.close
+  end
+
+  def test_locales_option
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    full_reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    full = full_reader.get('81.2.69.160')
+    full_reader.close
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path, locales: %w[de en])
+    record = reader.get('81.2.69.160')
+
+    assert_equal full['country']['names'].slice('de', 'en'), record['country']['names']
+    assert_equal full['city']['names'].slice('de', 'en'), record['city']['names']
+    assert_equal full['subdivisions'][0]['names'].slice('de', 'en'),
+                 record['subdivisions'][0]['names']
+    assert_equal full['country']['iso_code'], record['country']['iso_code']
+    assert_equal full['country']['names'].slice('de', 'en'),
+                 reader.dig('81.2.69.160', 'country', 'names')
+
+    reader.close
+  end
+
+  def test_collapse_names_option
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    full_reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+    full = full_reader.get('81.2.69.160')
+    full_reader.close
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path, locales: %w[xx de en], collapse_names: true)
+    record = reader.get('81.2.69.160')
+
+    assert_equal full['country']['names']['de'], record['country']['names']
+    assert_equal full['city']['names']['de'], record['city']['names']
+    assert_equal full['country']['names']['de'], reader.dig('81.2.69.160', 'country', 'names')
+
+    assert_equal full['country']['names']['en'],
+                 reader.get('81.2.69.160', locales: %w[en de])['country']['names']
+    assert_nil reader.get('81.2.69.160', locales: %w[xx])['country']['names']
+
+    uncollapsed = reader.get('81.2.69.160', collapse_names: false)
+
+    assert_equal full['country']['names'].slice('de', 'en'), uncollapsed['country']['names']
+
+    reader.close
+  end
+
+  def test_collapse_names_requires_locales
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    assert_raises(ArgumentError) do
+      MaxMind::DB::Rust::Reader.new(test_db_path, collapse_names: true)
+    end
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path)
+
+    assert_raises(ArgumentError) { reader.get('81.2.69.160', collapse_names: true) }
+
+    reader.close
+  end
+
+  def test_symbol_keys_option
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path, keys: :symbol)
+    record = reader.get('81.2.69.160')
+
+    assert_equal 'GB', record[:country][:iso_code]
+    assert_equal 'London', record[:city][:names][:en]
+    assert(record[:subdivisions].all? { |s| s.keys.all?(Symbol) })
+    assert_equal 'GB', reader.get('81.2.69.160', %w[country iso_code])
+    assert_equal 'London', reader.dig('81.2.69.160', 'city', 'names')[:en]
+
+    assert_equal 'GB', reader.get('81.2.69.160', keys: :string)['country']['iso_code']
+    assert_raises(ArgumentError) { reader.get('81.2.69.160', keys: :bogus) }
+
+    reader.close
+  end
+
+  def test_freeze_option
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path, freeze: true)
+    record = reader.get('81.2.69.160')
+
+    assert_predicate record, :frozen?
+    assert_predicate record['country'], :frozen?
+    assert_predicate record['subdivisions'], :frozen?
+    assert_predicate record['subdivisions'][0], :frozen?
+    assert_predicate record['city']['names']['en'], :frozen?
+    assert_predicate record['location']['time_zone'], :frozen?
+
+    assert_raises(FrozenError) { record['country']['iso_code'] = 'XX' }
+    refute_predicate reader.get('81.2.69.160', freeze: false), :frozen?
+
+    reader.close
+  end
+
+  def test_freeze_with_symbol_keys_is_ractor_shareable
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path, keys: :symbol, freeze: true)
+    record = reader.get('81.2.69.160')
+
+    assert Ractor.shareable?(record)
+
+    reader.close
+  end
+
+  def test_record_cache
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, cache_size: 10)
+
+    assert_equal({ hits: 0, misses: 0, size: 0, capacity: 10 }, reader.cache_stats)
+
+    first = reader.get('1.1.1.3')
+    second = reader.get('1.1.1.2')
+
+    assert_same first, second
+    assert_predicate first, :frozen?
+    assert_equal({ hits: 1, misses: 1, size: 1, capacity: 10 }, reader.cache_stats)
+
+    # get_with_prefix_length and get_many share the cache
+    record, prefix_length = reader.get_with_prefix_length('1.1.1.3')
+
+    assert_same first, record
+    assert_equal 31, prefix_length
+    assert_same first, reader.get_many(['1.1.1.3']).first
+
+    # Lookups with per-call options bypass the cache
+    refute_same first, reader.get('1.1.1.3', keys: :symbol)
+    assert_equal 3, reader.cache_stats[:hits]
+
+    # Addresses without data are not cached
+    assert_nil reader.get('2.2.2.2')
+    assert_equal 1, reader.cache_stats[:size]
+
+    reader.close
+
+    assert_equal 0, reader.cache_stats[:size]
+
+    assert_raises(ArgumentError) { MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, cache_size: 10, freeze: false) }
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, cache_size: 10, freeze: true)
+    refute_predicate reader.get('1.1.1.3', freeze: false), :frozen?
+    reader.close
+  end
+
+  def test_record_cache_eviction
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, cache_size: 1)
+
+    reader.get('1.1.1.1')
+    reader.get('1.1.1.2')
+    reader.get('1.1.1.1')
+
+    assert_equal({ hits: 0, misses: 3, size: 1, capacity: 1 }, reader.cache_stats)
+
+    reader.close
+  end
+
+  def test_record_cache_survives_gc
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(test_db_path, cache_size: 100)
+    expected = reader.get('81.2.69.160').to_a.inspect
+
+    GC.start(full_mark: true, immediate_sweep: true)
+    GC.compact if GC.respond_to?(:compact)
+
+    assert_equal expected, reader.get('81.2.69.160').to_a.inspect
+    assert_equal 1, reader.cache_stats[:hits]
+
+    reader.close
+  end
+
+  def test_record_cache_disabled
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+
+    assert_nil reader.cache_stats
+    refute_same reader.get('1.1.1.1'), reader.get('1.1.1.1')
+
+    reader.close
+  end
+
+  def test_reload
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    build_epoch = reader.metadata.build_epoch
+
+    assert_same reader, reader.reload!
+    assert_equal build_epoch, reader.metadata.build_epoch
+    assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+
+    reader.close
+  end
+
+  def test_reload_different_database
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path) && File.exist?(ipv6_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    assert_raises(ArgumentError) { reader.get('::1:ffff:ffff') }
+
+    reader.reload!(ipv6_test_db_path, mode: MaxMind::DB::Rust::MODE_MEMORY)
+
+    assert_equal 6, reader.metadata.ip_version
+    assert_equal({ 'ip' => '::1:ffff:ffff' }, reader.get('::1:ffff:ffff'))
+
+    # Later reloads default to the most recently loaded path
+    reader.reload!
+    assert_equal 6, reader.metadata.ip_version
+
+    reader.close
+  end
+
+  def test_reload_invalid_database_keeps_current
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+
+    assert_raises(MaxMind::DB::Rust::InvalidDatabaseError) { reader.reload!(__FILE__) }
+    assert_raises(Errno::ENOENT) { reader.reload!('/nonexistent/path/to/database.mmdb') }
+    assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+
+    reader.close
+  end
+
+  def test_reload_invalidates_record_cache
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, cache_size: 10)
+    reader.get('1.1.1.1')
+    reader.reload!
+
+    assert_equal 0, reader.cache_stats[:size]
+    assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+
+    reader.close
+  end
+
+  def test_reload_after_close
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = MaxMind::DB::Rust::Reader.new(ipv4_test_db_path)
+    reader.close
+
+    error = assert_raises(RuntimeError) { reader.reload! }
+    assert_match(/closed/, error.message)
+    assert reader.closed
+  end
+
+  def test_auto_reload
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path) && File.exist?(ipv6_test_db_path)
+
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'test.mmdb')
+      FileUtils.cp(ipv4_test_db_path, path)
+      errors = []
+      reader = MaxMind::DB::Rust::Reader.new(
+        path, auto_reload: true, check_interval: 0, on_reload_error: ->(error) { errors << error }
+      )
+      assert_equal 4, reader.metadata.ip_version
+
+      # Replace the file the way geoipupdate does: write elsewhere, then rename
+      replace_file(ipv6_test_db_path, path)
+      assert_equal({ 'ip' => '::1:ffff:ffff' }, reader.get('::1:ffff:ffff'))
+      assert_equal 6, reader.metadata.ip_version
+      assert_nil reader.last_reload_error
+
+      replace_file(__FILE__, path)
+      assert_equal({ 'ip' => '::1:ffff:ffff' }, reader.get('::1:ffff:ffff'))
+      assert_instance_of MaxMind::DB::Rust::InvalidDatabaseError, reader.last_reload_error
+      assert_equal [reader.last_reload_error], errors
+
+      # A broken file is reported once, not on every lookup
+      reader.get('::1:ffff:ffff')
+      assert_equal 1, errors.size
+
+      replace_file(ipv4_test_db_path, path)
+      assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+      assert_nil reader.last_reload_error
+
+      reader.close
+    end
+  end
+
+  def test_auto_reload_check_interval
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path) && File.exist?(ipv6_test_db_path)
+
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'test.mmdb')
+      FileUtils.cp(ipv4_test_db_path, path)
+      reader = MaxMind::DB::Rust::Reader.new(path, auto_reload: true, check_interval: 3600)
+
+      replace_file(ipv6_test_db_path, path)
+      assert_equal 4, reader.metadata.ip_version
+
+      reader.close
+    end
+  end
+
+  def test_auto_reload_options_require_auto_reload
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    assert_raises(ArgumentError) do
+      MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, check_interval: 10)
+    end
+    assert_raises(ArgumentError) do
+      MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, auto_reload: true, check_interval: -1)
+    end
+    assert_nil MaxMind::DB::Rust::Reader.new(ipv4_test_db_path).last_reload_error
+  end
+
+  def test_from_bytes
+    skip 'Test database not found' unless File.exist?(test_db_path)
+
+    bytes = File.binread(test_db_path).freeze
+    reader = MaxMind::DB::Rust::Reader.from_bytes(bytes, locales: ['en'], collapse_names: true)
+
+    GC.start(full_mark: true, immediate_sweep: true)
+    GC.compact if GC.respond_to?(:compact)
+
+    assert_equal 'London', reader.get('81.2.69.160')['city']['names']
+    assert_equal 'GeoIP2-City', reader.metadata.database_type
+
+    reader.close
+  end
+
+  def test_from_bytes_copies_unfrozen_string
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    bytes = File.binread(ipv4_test_db_path)
+    reader = MaxMind::DB::Rust::Reader.from_bytes(bytes)
+    bytes.replace('')
+
+    assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+
+    reader.close
+  end
+
+  def test_from_bytes_invalid_database
+    error = assert_raises(MaxMind::DB::Rust::InvalidDatabaseError) do
+      MaxMind::DB::Rust::Reader.from_bytes('not a database')
+    end
+    assert_match(/valid MaxMind DB file/, error.message)
+
+    assert_raises(ArgumentError) do
+      MaxMind::DB::Rust::Reader.from_bytes('', mode: MaxMind::DB::Rust::MODE_MEMORY)
+    end
+  end
+
+  def test_from_io
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    reader = File.open(ipv4_test_db_path, 'rb') { |io| MaxMind::DB::Rust::Reader.from_io(io) }
+    assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+    reader.close
+
+    reader = MaxMind::DB::Rust::Reader.from_io(StringIO.new(File.binread(ipv4_test_db_path)))
+    assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+
+    # No file to fall back on, but an explicit path works
+    assert_raises(ArgumentError) { reader.reload! }
+    reader.reload!(ipv4_test_db_path)
+    assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+
+    reader.close
+  end
+
+  def test_gzip_database
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'test.mmdb.gz')
+      Zlib::GzipWriter.open(path) { |gz| gz.write(File.binread(ipv4_test_db_path)) }
+
+      [MaxMind::DB::Rust::MODE_MMAP, MaxMind::DB::Rust::MODE_MEMORY].each do |mode|
+        reader = MaxMind::DB::Rust::Reader.new(path, mode: mode)
+        assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+        reader.close
+      end
+
+      reader = MaxMind::DB::Rust::Reader.from_bytes(File.binread(path).freeze)
+      assert_equal({ 'ip' => '1.1.1.1' }, reader.get('1.1.1.1'))
+      reader.close
+    end
+  end
+
+  def test_tar_gz_database
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path) && File.exist?(ipv6_test_db_path)
+
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, 'test.tar.gz')
+      File.binwrite(path, tar_gz(
+        'GeoIP2-Test_20240101/COPYRIGHT.txt' => 'Copyright',
+        'GeoIP2-Test_20240101/ipv4.mmdb' => File.binread(ipv4_test_db_path),
+        'GeoIP2-Test_20240101/ipv6.mmdb' => File.binread(ipv6_test_db_path)
+      ))
+
+      reader = MaxMind::DB::Rust::Reader.new(path)
+      assert_equal 4, reader.metadata.ip_version
+
+      reader.reload!(path, member: 'ipv6.mmdb')
+      assert_equal 6, reader.metadata.ip_version
+      reader.reload!
+      assert_equal 6, reader.metadata.ip_version
+      reader.close
+
+      reader = MaxMind::DB::Rust::Reader.new(path, member: 'GeoIP2-Test_20240101/ipv6.mmdb')
+      assert_equal 6, reader.metadata.ip_version
+      reader.close
+
+      error = assert_raises(MaxMind::DB::Rust::InvalidDatabaseError) do
+        MaxMind::DB::Rust::Reader.new(path, member: 'missing.mmdb')
+      end
+      assert_match(/no member named missing.mmdb/, error.message)
+    end
+  end
+
+  def test_member_requires_tar_archive
+    skip 'Test database not found' unless File.exist?(ipv4_test_db_path)
+
+    assert_raises(ArgumentError) do
+      MaxMind::DB::Rust::Reader.new(ipv4_test_db_path, member: 'test.mmdb')
+    end
+  end
+
+  def test_corrupt_gzip_database
+    error = assert_raises(MaxMind::DB::Rust::InvalidDatabaseError) do
+      MaxMind::DB::Rust::Reader.from_bytes(Zlib.gzip('not a database')[0, 12])
+    end
+    assert_match(/gzip/, error.message)
+  end
+
+  def test_tar_member_with_lying_size
+    tar = StringIO.new(String.new)
+    Gem::Package::TarWriter.new(tar) do |writer|
+      writer.add_file_simple('GeoIP2-City.mmdb', 0o644, 10) { |io| io.write('x' * 10) }
+    end
+    header = tar.string.b
+    # Claim an 8 GiB member, with the header checksum updated to match
+    header[124, 12] = format("%011o\0", 2**33 - 1)
+    header[148, 8] = ' ' * 8
+    header[148, 8] = format("%06o\0 ", header[0, 512].sum(0))
+
+    error = assert_raises(MaxMind::DB::Rust::InvalidDatabaseError) do
+      MaxMind::DB::Rust::Reader.from_bytes(header)
+    end
+    assert_match(/truncated|corrupt/, error.message)
+  end
+
+  private
+
+  def tar_gz(files)
+    tar = StringIO.new(String.new)
+    Gem::Package::TarWriter.new(tar) do |writer|
+      files.each do |name, data|
+        writer.add_file_simple(name, 0o644, data.bytesize) { |io| io.write(data) }
+      end
+    end
+    Zlib.gzip(tar.string)
+  end
+
+  def replace_file(source, path)
+    tmp = "#{path}.tmp"
+    FileUtils.cp(source, tmp)
+    File.rename(tmp, path)
+  end
+
+  def ipv4_test_db_path
+    File.join(TEST_DATA_DIR, 'MaxMind-DB-test-ipv4-24.mmdb')
+  end
 
   def test_db_path
     File.join(TEST_DATA_DIR, 'GeoIP2-City-Test.mmdb')
@@ -0,0 +1,282 @@
+# frozen_string_literal: true
+
+require 'test_helper'
+require 'fileutils'
+require 'tmpdir'
+
+class WriterTest < Minitest::Test
+  def setup
+    @dir = Dir.mktmpdir
+    @path = File.join(@dir, 'test.mmdb')
+  end
+
+  def teardown
+    FileUtils.rm_rf(@dir)
+  end
+
+  def test_round_trip
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test-Allowlist', languages: %w[en de],
+                                           description: { 'en' => 'Test database' })
+    writer.insert('10.0.0.0/8', { 'name' => 'corp', 'allowed' => true })
+    writer.insert('2001:db8::/32', { 'name' => 'docs' })
+    writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_equal({ 'name' => 'corp', 'allowed' => true }, reader.get('10.1.2.3'))
+    assert_equal [{ 'name' => 'corp', 'allowed' => true }, 8], reader.get_with_prefix_length('10.255.0.1')
+    assert_equal({ 'name' => 'docs' }, reader.get('2001:db8::1'))
+    assert_nil reader.get('11.0.0.1')
+
+    metadata = reader.metadata
+    assert_equal 'Test-Allowlist', metadata.database_type
+    assert_equal 6, metadata.ip_version
+    assert_equal 28, metadata.record_size
+    assert_equal %w[en de], metadata.languages
+    assert_equal({ 'en' => 'Test database' }, metadata.description)
+    assert_in_delta Time.now.to_i, metadata.build_epoch, 60
+
+    reader.close
+  end
+
+  def test_value_types
+    data = {
+      'string' => 'héllo',
+      'bytes' => "\x00\xFF".b,
+      'uint16' => 65_535,
+      'uint32' => 4_294_967_295,
+      'uint64' => 2**64 - 1,
+      'uint128' => 2**128 - 1,
+      'int32' => -2_147_483_648,
+      'double' => 3.14,
+      'true' => true,
+      'false' => false,
+      'array' => [1, 'two', [3.0]],
+      'map' => { 'nested' => { 'deep' => 'value' } },
+      symbol: :value
+    }
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test')
+    writer.insert('1.2.3.0/24', data)
+    writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    record = reader.get('1.2.3.4')
+    assert_equal data.transform_keys(&:to_s).merge('symbol' => 'value'), record
+    assert_equal Encoding::BINARY, record['bytes'].encoding
+
+    reader.close
+  end
+
+  def test_record_sizes
+    [24, 28, 32].each do |record_size|
+      writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4, record_size: record_size)
+      (0...300).each { |i| writer.insert("10.#{i / 256}.#{i % 256}.0/24", { 'i' => i }) }
+      writer.write(@path)
+
+      reader = MaxMind::DB::Rust::Reader.new(@path)
+      assert_equal record_size, reader.metadata.record_size
+      assert_equal 4, reader.metadata.ip_version
+      assert_equal({ 'i' => 299 }, reader.get('10.1.43.7'))
+      assert_equal 300, reader.each.count
+      reader.close
+    end
+  end
+
+  def test_overlapping_insert_replaces_subnet
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    writer.insert('10.0.0.0/16', { 'zone' => 'outer' })
+    writer.insert(IPAddr.new('10.0.1.0/24'), { 'zone' => 'inner' })
+    writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_equal [{ 'zone' => 'outer' }, 24], reader.get_with_prefix_length('10.0.0.1')
+    assert_equal [{ 'zone' => 'inner' }, 24], reader.get_with_prefix_length('10.0.1.1')
+    assert_equal [{ 'zone' => 'outer' }, 23], reader.get_with_prefix_length('10.0.2.1')
+    reader.close
+  end
+
+  def test_merge_strategies
+    vendor = { 'country' => { 'iso_code' => 'US', 'name' => 'United States' }, 'asn' => 64_500 }
+    annotation = { 'country' => { 'iso_code' => 'CA' }, 'team' => 'infra' }
+    expected = {
+      replace: annotation,
+      shallow: { 'country' => { 'iso_code' => 'CA' }, 'asn' => 64_500, 'team' => 'infra' },
+      deep: { 'country' => { 'iso_code' => 'CA', 'name' => 'United States' }, 'asn' => 64_500, 'team' => 'infra' },
+      keep: vendor
+    }
+
+    expected.each do |merge, record|
+      writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+      writer.insert('10.0.0.0/16', vendor)
+      writer.insert('10.0.1.0/24', annotation, merge: merge)
+      writer.write(@path)
+
+      reader = MaxMind::DB::Rust::Reader.new(@path)
+      assert_equal record, reader.get('10.0.1.1'), "merge: #{merge}"
+      assert_equal vendor, reader.get('10.0.2.1'), "merge: #{merge}"
+      reader.close
+    end
+
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    assert_raises(ArgumentError) { writer.insert('10.0.0.0/16', vendor, merge: :append) }
+  end
+
+  def test_keep_fills_gaps
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    writer.insert('10.0.1.0/24', { 'source' => 'vendor' })
+    writer.insert('10.0.0.0/16', { 'source' => 'default' }, merge: :keep)
+    writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_equal({ 'source' => 'vendor' }, reader.get('10.0.1.1'))
+    assert_equal({ 'source' => 'default' }, reader.get('10.0.200.1'))
+    reader.close
+  end
+
+  def test_remove
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    writer.insert('10.0.0.0/16', { 'zone' => 'corp' })
+    writer.remove('10.0.1.0/24')
+    writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_nil reader.get('10.0.1.1')
+    assert_equal({ 'zone' => 'corp' }, reader.get('10.0.0.1'))
+    assert_equal({ 'zone' => 'corp' }, reader.get('10.0.2.1'))
+    reader.close
+  end
+
+  def test_adjacent_identical_networks_are_merged
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    writer.insert('10.0.0.0/25', { 'zone' => 'corp' })
+    writer.insert('10.0.0.128/25', { 'zone' => 'corp' })
+    writer.insert('10.0.1.0/24', { 'zone' => 'lab' })
+    writer.remove('10.0.1.0/24')
+    writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_equal [['10.0.0.0', 24]], reader.each.map { |network, _data| [network.to_s, network.prefix] }
+    reader.close
+  end
+
+  def test_ipv4_aliases
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test')
+    writer.insert('1.2.3.0/24', { 'zone' => 'corp' })
+    writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_equal({ 'zone' => 'corp' }, reader.get('::ffff:1.2.3.4'))
+    assert_equal({ 'zone' => 'corp' }, reader.get('2002:102:304::1'))
+    assert_equal 1, reader.each.count
+    assert_equal 3, reader.each(include_aliased: true).count
+    reader.close
+
+    assert_raises(ArgumentError) { writer.insert('::ffff:1.2.3.0/120', { 'zone' => 'lab' }) }
+    assert_raises(ArgumentError) { writer.remove('2002::/24') }
+
+    # Networks containing an alias leave it in place
+    writer.insert('::/0', { 'zone' => 'default' }, merge: :keep)
+    writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_equal({ 'zone' => 'corp' }, reader.get('2002:102:304::1'))
+    assert_equal({ 'zone' => 'default' }, reader.get('2002:505:505::1'))
+    assert_equal({ 'zone' => 'default' }, reader.get('2001:db8::1'))
+    reader.close
+  end
+
+  def test_ipv4_aliases_disabled
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', alias_ipv6_to_ipv4: false)
+    writer.insert('1.2.3.0/24', { 'zone' => 'corp' })
+    writer.insert('2002::/16', { 'zone' => '6to4' })
+    writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_nil reader.get('::ffff:1.2.3.4')
+    assert_equal({ 'zone' => '6to4' }, reader.get('2002:102:304::1'))
+    assert_equal 2, reader.each(include_aliased: true).count
+    reader.close
+  end
+
+  def test_data_section_deduplication
+    countries = {
+      'US' => { 'iso_code' => 'US', 'names' => { 'en' => 'United States', 'de' => 'USA' } },
+      'CA' => { 'iso_code' => 'CA', 'names' => { 'en' => 'Canada', 'de' => 'Kanada' } }
+    }
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    (0...512).each do |i|
+      country = i.even? ? countries['US'] : countries['CA']
+      writer.insert("10.#{i / 256}.#{i % 256}.0/24", { 'country' => country, 'registered_country' => country, 'id' => i })
+    end
+    stats = writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_equal({ 'country' => countries['CA'], 'registered_country' => countries['CA'], 'id' => 301 },
+                 reader.get('10.1.45.1'))
+    assert_equal 512, reader.each.count
+    assert_equal reader.metadata.node_count, stats[:node_count]
+    assert_equal File.size(@path), stats[:file_size]
+
+    assert_operator stats[:data_section_size], :<, stats[:undeduplicated_size]
+    assert_in_delta stats[:undeduplicated_size].fdiv(stats[:data_section_size]), stats[:dedup_ratio], 0.001
+    assert_operator stats[:dedup_ratio], :>, 3
+
+    reader.close
+  end
+
+  def test_identical_records_are_stored_once
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    writer.insert('10.0.0.0/24', { 'zone' => 'corp' })
+    writer.insert('10.0.2.0/24', { 'zone' => 'corp' })
+    stats = writer.write(@path)
+
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_equal reader.lookup('10.0.0.1').data_offset, reader.lookup('10.0.2.1').data_offset
+    assert_equal stats[:undeduplicated_size], stats[:data_section_size] * 2
+    reader.close
+  end
+
+  def test_invalid_arguments
+    assert_raises(ArgumentError) { MaxMind::DB::Rust::Writer.new }
+    assert_raises(ArgumentError) { MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 5) }
+    assert_raises(ArgumentError) { MaxMind::DB::Rust::Writer.new(database_type: 'Test', record_size: 16) }
+
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    assert_raises(ArgumentError) { writer.insert('2001:db8::/32', { 'a' => 1 }) }
+    assert_raises(ArgumentError) { writer.insert('not a network', { 'a' => 1 }) }
+    assert_raises(ArgumentError) { writer.insert('1.2.3.0/24', { 'a' => nil }) }
+    assert_raises(TypeError) { writer.insert('1.2.3.0/24', { 'a' => Object.new }) }
+    assert_raises(TypeError) { writer.insert('1.2.3.0/24', { 1 => 'a' }) }
+    assert_raises(RangeError) { writer.insert('1.2.3.0/24', { 'a' => -2**40 }) }
+  end
+
+  def test_nesting_limit
+    writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+    recursive = {}
+    recursive['self'] = recursive
+    assert_raises(ArgumentError) { writer.insert('1.2.3.0/24', recursive) }
+
+    deep = 600.times.reduce('leaf') { |value, _| [value] }
+    assert_raises(ArgumentError) { writer.insert('1.2.3.0/24', { 'deep' => deep }) }
+
+    nested = 100.times.reduce('leaf') { |value, _| { 'next' => value } }
+    writer.insert('1.2.3.0/24', nested)
+    writer.write(@path)
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_equal nested, reader.get('1.2.3.4')
+    reader.close
+  end
+
+  def test_concurrent_writes_to_one_path
+    writers = %w[a b c d].map do |name|
+      writer = MaxMind::DB::Rust::Writer.new(database_type: 'Test', ip_version: 4)
+      writer.insert('1.2.3.0/24', { 'name' => name })
+      writer
+    end
+    writers.flat_map { |writer| 5.times.map { Thread.new { writer.write(@path) } } }.each(&:join)
+
+    assert_equal ['test.mmdb'], Dir.children(@dir)
+    reader = MaxMind::DB::Rust::Reader.new(@path)
+    assert_includes %w[a b c d], reader.get('1.2.3.4')['name']
+    reader.close
+  end
+end